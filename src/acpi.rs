@@ -0,0 +1,140 @@
+/*
+ACPI table lookup
+
+The firmware describes the hardware in ACPI tables in physical memory:
+- the RSDP (root system description pointer) is found by scanning the first KiB of the
+  extended BIOS data area (EBDA) and the BIOS area 0xE0000 to 0xFFFFF for "RSD PTR ",
+  on 16 byte boundaries
+- the RSDP points to the RSDT (32-bit table addresses), or since ACPI 2.0 to the
+  XSDT (64-bit table addresses)
+- each table starts with a header with a 4 byte signature (e.g. "HPET", "APIC"),
+  and all bytes of a table (or the RSDP) sum to zero
+
+The tables are read through the mapping of physical memory at the physical memory offset,
+so this needs the memory manager. Only the lookup is implemented, the users of a table
+parse it themselves (e.g. time::hpet)
+*/
+use core::mem;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::memory::MEMORY_MANAGER;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// the real mode segment of the EBDA is stored in the BIOS data area
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+
+// the RSDP, the fields after rsdt_address exist since revision 2 (ACPI 2.0)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3]
+}
+
+// the header of all system description tables
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32
+}
+
+
+// the virtual address of phys in the mapping of physical memory
+fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    let physical_memory_offset = MEMORY_MANAGER.lock()
+        .as_ref()
+        .expect("memory manager not initialized")
+        .mapper
+        .phys_offset();
+    physical_memory_offset + phys.as_u64()
+}
+
+/*
+Read a T at physical address phys
+unsafe: phys must be the address of a T in memory
+*/
+pub unsafe fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    // ACPI structures are packed, so they can be unaligned
+    phys_to_virt(phys).as_ptr::<T>().read_unaligned()
+}
+
+// check the len bytes at phys sum to zero
+unsafe fn checksum_valid(phys: PhysAddr, len: usize) -> bool {
+    let bytes = core::slice::from_raw_parts(phys_to_virt(phys).as_ptr::<u8>(), len);
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+
+// search the RSDP in len bytes at physical address start
+fn scan_rsdp(start: u64, len: u64) -> Option<(PhysAddr, Rsdp)> {
+    (start..start + len).step_by(16).map(PhysAddr::new).find_map(|phys| {
+        let rsdp = unsafe { read_phys::<Rsdp>(phys) };
+        // the checksum of revision 0 only covers the fields up to rsdt_address
+        if &rsdp.signature == RSDP_SIGNATURE && unsafe { checksum_valid(phys, 20) } {
+            Some((phys, rsdp))
+        } else {
+            None
+        }
+    })
+}
+
+fn find_rsdp() -> Option<(PhysAddr, Rsdp)> {
+    let ebda = unsafe { read_phys::<u16>(PhysAddr::new(EBDA_SEGMENT_POINTER)) } as u64 * 16;
+    let in_ebda = if ebda != 0 { scan_rsdp(ebda, 1024) } else { None };
+    in_ebda.or_else(|| scan_rsdp(BIOS_AREA_START, BIOS_AREA_END - BIOS_AREA_START))
+}
+
+// the physical address of the RSDP, None if there is no ACPI
+pub fn rsdp_address() -> Option<PhysAddr> {
+    find_rsdp().map(|(phys, _)| phys)
+}
+
+/*
+Find the table with signature, return its physical address (the start of its header)
+Return None if there is no ACPI, no such table, or the table has an invalid checksum
+*/
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let (rsdp_phys, rsdp) = find_rsdp()?;
+    // use the XSDT if there is one, its table addresses are 64-bit
+    let (root, entry_size) = if rsdp.revision >= 2 && unsafe { checksum_valid(rsdp_phys, rsdp.length as usize) }
+        && rsdp.xsdt_address != 0 {
+        (PhysAddr::new(rsdp.xsdt_address), 8)
+    } else {
+        (PhysAddr::new(rsdp.rsdt_address as u64), 4)
+    };
+
+    let header = unsafe { read_phys::<SdtHeader>(root) };
+    let entries = (header.length as usize).saturating_sub(mem::size_of::<SdtHeader>()) / entry_size;
+    (0..entries).find_map(|i| {
+        let entry = root + mem::size_of::<SdtHeader>() + i * entry_size;
+        let table = unsafe {
+            if entry_size == 8 { read_phys::<u64>(entry) } else { read_phys::<u32>(entry) as u64 }
+        };
+        let table = PhysAddr::new(table);
+        let table_header = unsafe { read_phys::<SdtHeader>(table) };
+        if &table_header.signature == signature
+            && unsafe { checksum_valid(table, table_header.length as usize) } {
+            Some(table)
+        } else {
+            None
+        }
+    })
+}
@@ -1,24 +1,68 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
+        mapper::MapToError, PageSize, PageTableFlags, Size4KiB
     },
     VirtAddr
 };
-use linked_list_allocator::LockedHeap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::memory::{MemoryManager, MEMORY_MANAGER};
+use crate::memory::vma::{self, AreaKind};
 
 // custom allocators
 pub mod bump_allocator;
 pub mod linked_list;
 pub mod fixed_size_block;
+pub mod slab;
+pub mod stats;    // heap statistics
+pub mod oom;    // out-of-memory handling
+#[cfg(feature = "heap-debug")]
+pub mod debug;    // heap debugging checks
+#[cfg(feature = "kasan")]
+pub mod kasan;    // shadow memory sanitizer
 
+#[cfg(feature = "alloc-bump")]
 use bump_allocator::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
 use linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-external")]
+use linked_list_allocator::LockedHeap;
 
+#[cfg(feature = "alloc-fixed-block")]
 use self::fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-slab")]
+use self::slab::SlabAllocator;
+use self::stats::HeapStats;
+
+// exactly one heap allocator needs to be selected with cargo features
+#[cfg(not(any(
+    feature = "alloc-bump", feature = "alloc-linked-list", feature = "alloc-fixed-block",
+    feature = "alloc-slab", feature = "alloc-external"
+)))]
+compile_error!("no heap allocator selected, enable one of the alloc-* features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", any(feature = "alloc-linked-list", feature = "alloc-fixed-block", feature = "alloc-slab", feature = "alloc-external")),
+    all(feature = "alloc-linked-list", any(feature = "alloc-fixed-block", feature = "alloc-slab", feature = "alloc-external")),
+    all(feature = "alloc-fixed-block", any(feature = "alloc-slab", feature = "alloc-external")),
+    all(feature = "alloc-slab", feature = "alloc-external")
+))]
+compile_error!("multiple heap allocators selected, use --no-default-features to replace the default allocator");
 
 // the virtual memory allocated for the heap
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024;    // the current heap size is 100 KB
+pub const HEAP_SIZE: usize = 100 * 1024;    // the initial heap size is 100 KB
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024;  // the default upper limit of heap size is 16 MB
+// the virtual memory reserved for the heap in memory::vma, the heap limit cannot exceed it
+pub const HEAP_AREA_SIZE: usize = 1024 * 1024 * 1024;
+// the minimum size mapped each time the heap grows, to avoid growing on every allocation
+const HEAP_GROW_SIZE: usize = 64 * 1024;
+
+// the end of the mapped heap memory and the upper limit of heap size
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 
 // Locked is initially created to implement allocators, but it can have other uses as well
@@ -57,36 +101,37 @@ fn align_up(addr: usize, align: usize) -> usize {
 }
 
 
-// initialize heap with given page table mapper and heap memory allocator
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> Result<(), MapToError<Size4KiB>> {
-    // get the range of pages to allocate
-    let page_range = {
-        // convert heap start and (inclusive) end constants to virtual addresses
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        // get the pages that contain the virtual addresses
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        // create a range of pages between start and end address
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        // allocate a physical frame
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        // set the (virtual) page to be PRESENT and WRITABLE
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            // create the page table mapping from the page to the physical frame
-            // flush() add the page to translation lookaside buffer
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+// reallocate by allocating a new memory block and copying the data, like the default GlobalAlloc::realloc
+// used by the heap allocators when an allocation cannot be resized in place
+unsafe fn realloc_by_copy(
+    allocator: &impl GlobalAlloc,
+    ptr: *mut u8,
+    layout: Layout,
+    new_size: usize
+) -> *mut u8 {
+    let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+    let new_ptr = allocator.alloc(new_layout);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+        allocator.dealloc(ptr, layout);
     }
+    new_ptr
+}
+
+
+// initialize heap with the mapper and frame allocator stored in memory::MEMORY_MANAGER
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    // the heap grows into the reserved area, so no other area can be placed after the heap end
+    vma::reserve_at(VirtAddr::new(HEAP_START as u64), HEAP_AREA_SIZE as u64, AreaKind::Heap, "kernel heap")
+        .expect("heap virtual memory already reserved");
+    #[cfg(feature = "kasan")]
+    kasan::init();
+    {
+        let mut memory_manager = MEMORY_MANAGER.lock();
+        let memory_manager = memory_manager.as_mut().expect("memory manager not initialized");
+        map_heap_pages(memory_manager, HEAP_START, HEAP_SIZE)?;
+    }   // release MEMORY_MANAGER before locking the allocator
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
 
     // assign the newly allocated memory to heap allocator
     unsafe {
@@ -97,16 +142,270 @@ pub fn init_heap(
 }
 
 
+// map the virtual memory from start to start + size for heap
+// large heap growth is mapped with 2 MiB pages where possible
+fn map_heap_pages(memory_manager: &mut MemoryManager, start: usize, size: usize)
+    -> Result<(), MapToError<Size4KiB>>
+{
+    // set the (virtual) pages to be PRESENT and WRITABLE, heap memory is never executed
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory_manager.map_range(VirtAddr::new(start as u64), size as u64, flags)?;
+    #[cfg(feature = "kasan")]
+    kasan::map_shadow(memory_manager, start, size)?;
+    Ok(())
+}
+
+
+/*
+Grow the heap by mapping new pages directly after the current heap end
 
-// linked_list_allocator crate: 
-// static ALLOCATOR: LockedHeap = LockedHeap::empty();
+At least min_size bytes are mapped (rounded up to whole pages), and the heap never
+grows beyond the heap limit. Return the start address and size of the new memory,
+which the caller needs to add to its heap allocator
 
-// bump allocator:
-//static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+This is called by KernelAllocator when the heap allocator runs out of memory
+*/
+fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    // the memory manager is not available before initialization (e.g. in tests without heap)
+    // HEAP_END is read and updated while it is locked, so two growers never map the same pages
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let memory_manager = memory_manager.as_mut()?;
+
+    let heap_end = HEAP_END.load(Ordering::SeqCst);
+    let heap_limit = HEAP_START + HEAP_LIMIT.load(Ordering::SeqCst);
+    let min_size = align_up(min_size, Size4KiB::SIZE as usize);
+    if heap_end.checked_add(min_size)? > heap_limit {
+        return None;
+    }
+    let size = min_size.max(HEAP_GROW_SIZE).min(heap_limit - heap_end);
+    map_heap_pages(memory_manager, heap_end, size).ok()?;
+
+    HEAP_END.store(heap_end + size, Ordering::SeqCst);
+    Some((heap_end, size))
+}
+
+// the current size of the mapped heap memory
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
 
-// linkedlist allocator:
-//  #[global_allocator]
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+// the upper limit of heap size
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::SeqCst)
+}
+
+// set the upper limit of heap size, the heap never shrinks below its current size
+// and never grows beyond the reserved HEAP_AREA_SIZE
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_AREA_SIZE).max(heap_size()), Ordering::SeqCst);
+}
+
+
+// take a snapshot of heap statistics
+pub fn stats() -> HeapStats {
+    #[allow(unused_mut)]
+    let mut stats = HeapStats::new(heap_size());
+    #[cfg(feature = "alloc-fixed-block")]
+    {
+        let allocator = ALLOCATOR.lock();
+        stats.free_blocks = Some(allocator.free_block_counts());
+        stats.free_regions = Some(allocator.fallback_region_stats());
+    }
+    #[cfg(feature = "alloc-linked-list")]
+    {
+        stats.free_regions = Some(ALLOCATOR.lock().free_region_stats());
+    }
+    #[cfg(feature = "alloc-slab")]
+    {
+        stats.free_regions = Some(ALLOCATOR.lock().fallback_region_stats());
+    }
+    stats
+}
+
+// print heap statistics to serial
+pub fn print_stats() {
+    crate::serial_println!("{}", stats());
+    #[cfg(feature = "alloc-slab")]
+    for cache in ALLOCATOR.lock().caches() {
+        crate::serial_println!("{}", cache);
+    }
+}
+
+
+/*
+The global allocator of the kernel
+
+It forwards every request to ALLOCATOR (the heap allocator selected by cargo features),
+grows the heap when ALLOCATOR runs out of memory, and records heap statistics
+*/
+pub struct KernelAllocator;
+
+impl KernelAllocator {
+    // handle an allocation ALLOCATOR failed to satisfy: grow the heap, then reclaim memory
+    unsafe fn alloc_slow(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.grow_and_alloc(layout);
+        if !ptr.is_null() {
+            return ptr;
+        }
+        self.reclaim_and_alloc(layout)
+    }
 
+    // grow the heap so that the allocation fits into the new memory, and retry
+    unsafe fn grow_and_alloc(&self, layout: Layout) -> *mut u8 {
+        // the new memory needs to hold the allocation even if its start is not aligned
+        // the extra page covers the bookkeeping overhead of the heap allocator
+        let min_size = layout.size()
+            .checked_add(layout.align())
+            .and_then(|size| size.checked_add(Size4KiB::SIZE as usize));
+        match min_size.and_then(grow_heap) {
+            Some((start, size)) => {
+                extend_allocator(start, size);
+                ALLOCATOR.alloc(layout)
+            },
+            None => ptr::null_mut()
+        }
+    }
+
+    // release cached memory and call OOM handlers, and retry as long as memory is freed
+    // print a report of the heap if the allocation still fails
+    unsafe fn reclaim_and_alloc(&self, layout: Layout) -> *mut u8 {
+        const MAX_RETRIES: usize = 3;
+
+        for _ in 0..MAX_RETRIES {
+            // call both, even if the heap allocator already released memory
+            let shrunk = shrink_allocator();
+            let reclaimed = oom::reclaim(layout);
+            if !shrunk && !reclaimed {
+                break;
+            }
+            let ptr = ALLOCATOR.alloc(layout);
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+
+        oom::report(layout);
+        ptr::null_mut()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "kasan")]
+        let layout = kasan::layout(layout);
+        let mut ptr = ALLOCATOR.alloc(layout);
+        if ptr.is_null() {
+            ptr = self.alloc_slow(layout);
+        }
+
+        if ptr.is_null() {
+            stats::record_failure();
+        } else {
+            #[cfg(feature = "kasan")]
+            kasan::on_alloc(ptr, layout);
+            stats::record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kasan")]
+        let layout = kasan::layout(layout);
+        #[cfg(feature = "kasan")]
+        kasan::on_dealloc(ptr, layout);
+        ALLOCATOR.dealloc(ptr, layout);
+        stats::record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        #[cfg(feature = "kasan")]
+        let layout = kasan::layout(layout);
+        // the heap allocator resizes the allocation in place if possible
+        let mut new_ptr = ALLOCATOR.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            // the heap allocator is out of memory, move the allocation to the grown heap
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            new_ptr = self.alloc_slow(new_layout);
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                ALLOCATOR.dealloc(ptr, layout);
+            }
+        }
+
+        if new_ptr.is_null() {
+            stats::record_failure();
+        } else {
+            #[cfg(feature = "kasan")]
+            kasan::on_realloc(ptr, layout, new_ptr, new_size);
+            stats::record_realloc(layout.size(), new_size);
+        }
+        new_ptr
+    }
+}
+
+#[cfg(not(feature = "heap-debug"))]
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
\ No newline at end of file
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+// with heap-debug, every allocation is checked for heap errors before reaching KernelAllocator
+// the heap statistics then include the header and red zones of each allocation
+#[cfg(feature = "heap-debug")]
+#[global_allocator]
+static KERNEL_ALLOCATOR: debug::DebugAllocator<KernelAllocator> = debug::DebugAllocator::new(KernelAllocator);
+
+
+/*
+The heap allocator is selected at compile time with cargo features:
+    alloc-bump          bump allocator
+    alloc-linked-list   linkedlist allocator
+    alloc-fixed-block   fixed-size block allocator (default)
+    alloc-slab          slab allocator
+    alloc-external      linked_list_allocator crate
+e.g. cargo test --no-default-features --features alloc-bump
+*/
+#[cfg(feature = "alloc-bump")]
+static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+
+#[cfg(feature = "alloc-linked-list")]
+static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+
+#[cfg(feature = "alloc-fixed-block")]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+#[cfg(feature = "alloc-slab")]
+static ALLOCATOR: Locked<SlabAllocator> = Locked::new(SlabAllocator::new());
+
+#[cfg(feature = "alloc-external")]
+static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+// the name of the selected heap allocator
+#[cfg(feature = "alloc-bump")]
+pub const ALLOCATOR_NAME: &str = "bump";
+#[cfg(feature = "alloc-linked-list")]
+pub const ALLOCATOR_NAME: &str = "linked list";
+#[cfg(feature = "alloc-fixed-block")]
+pub const ALLOCATOR_NAME: &str = "fixed-size block";
+#[cfg(feature = "alloc-slab")]
+pub const ALLOCATOR_NAME: &str = "slab";
+#[cfg(feature = "alloc-external")]
+pub const ALLOCATOR_NAME: &str = "linked_list_allocator";
+
+// release memory cached by ALLOCATOR (free blocks or empty slabs), return true if any was released
+fn shrink_allocator() -> bool {
+    #[cfg(any(feature = "alloc-fixed-block", feature = "alloc-slab"))]
+    return ALLOCATOR.lock().shrink() > 0;
+    #[cfg(not(any(feature = "alloc-fixed-block", feature = "alloc-slab")))]
+    return false;
+}
+
+// add the new memory of a grown heap to ALLOCATOR
+unsafe fn extend_allocator(start: usize, size: usize) {
+    // the external heap can only be extended at its end, which is where the heap grows
+    #[cfg(feature = "alloc-external")]
+    {
+        let _ = start;
+        ALLOCATOR.lock().extend(size);
+    }
+    #[cfg(not(feature = "alloc-external"))]
+    ALLOCATOR.lock().extend(start, size);
+}
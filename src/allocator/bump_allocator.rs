@@ -45,6 +45,13 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    // extend the heap with a memory region directly after the current heap end
+    // unsafe: the caller needs to ensure the memory region is valid and unused
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        assert_eq!(addr, self.heap_end, "bump allocator can only grow at heap end");
+        self.heap_end += size;
+    }
 }
 
 
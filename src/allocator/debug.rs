@@ -0,0 +1,198 @@
+/*
+Heap debugging allocator (cargo feature heap-debug)
+
+DebugAllocator wraps another allocator and checks every deallocation.
+Each allocation is extended with a header and two red zones:
+
+    | padding | header | red zone | user memory | red zone |
+                                  ^ returned pointer
+
+- the header records the requested layout and whether the memory is allocated or freed
+- the red zones are filled with a canary pattern, a changed canary means the
+  program wrote before or after its memory
+- freed memory is filled with a poison pattern and kept in a quarantine for a while
+  before it is returned to the wrapped allocator. Freeing it again is detected
+  as double free, and a changed poison pattern means the memory was written after free
+
+When an error is detected, the offending address is reported to serial and the kernel panics
+*/
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr};
+use spin::Mutex;
+
+use crate::serial_println;
+use super::align_up;
+
+const RED_ZONE_SIZE: usize = 16;
+pub const CANARY: u8 = 0xCA;   // the pattern of red zones
+pub const POISON: u8 = 0xDE;   // the pattern of freed memory
+const QUARANTINE_SIZE: usize = 32;  // number of freed allocations kept before reuse
+
+// the states recorded in header
+const ALLOCATED: u64 = 0xA110_CA7E_A110_CA7E;
+const FREED: u64 = 0xF4EE_F4EE_F4EE_F4EE;
+
+// the header stored before the red zone of each allocation
+#[repr(C)]
+struct Header {
+    state: u64,
+    size: usize,
+    align: usize
+}
+
+// the header is at a fixed offset before the user memory
+const HEADER_OFFSET: usize = mem::size_of::<Header>() + RED_ZONE_SIZE;
+
+
+pub struct DebugAllocator<A> {
+    inner: A,
+    // freed allocations that are not returned to inner allocator yet (user pointer and layout)
+    quarantine: Mutex<([Option<(usize, Layout)>; QUARANTINE_SIZE], usize)>
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator {
+            inner,
+            quarantine: Mutex::new(([None; QUARANTINE_SIZE], 0))
+        }
+    }
+
+    // the offset of user memory from the start of the inner allocation
+    fn front_size(layout: &Layout) -> usize {
+        align_up(HEADER_OFFSET, Self::inner_align(layout))
+    }
+
+    fn inner_align(layout: &Layout) -> usize {
+        layout.align().max(mem::align_of::<Header>())
+    }
+
+    // the layout of the inner allocation containing header, red zones and user memory
+    fn inner_layout(layout: &Layout) -> Option<Layout> {
+        let size = Self::front_size(layout)
+            .checked_add(layout.size())?
+            .checked_add(RED_ZONE_SIZE)?;
+        Layout::from_size_align(size, Self::inner_align(layout)).ok()
+    }
+
+    unsafe fn header(ptr: *mut u8) -> *mut Header {
+        ptr.sub(HEADER_OFFSET) as *mut Header
+    }
+
+    // check the header, red zones and layout of an allocation being freed
+    unsafe fn check_allocation(ptr: *mut u8, layout: &Layout) {
+        let header = &*Self::header(ptr);
+        match header.state {
+            ALLOCATED => {},
+            FREED => report("double free", ptr, layout),
+            _ => report("free of invalid pointer or corrupted header", ptr, layout)
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            serial_println!("allocated with size {}, align {}", header.size, header.align);
+            report("mismatched layout on free", ptr, layout);
+        }
+
+        let front_red_zone = ptr.sub(RED_ZONE_SIZE);
+        if !is_filled(front_red_zone, RED_ZONE_SIZE, CANARY) {
+            report("red zone before allocation overwritten (buffer underflow)", ptr, layout);
+        }
+        let back_red_zone = ptr.add(layout.size());
+        if !is_filled(back_red_zone, RED_ZONE_SIZE, CANARY) {
+            report("red zone after allocation overwritten (buffer overflow)", ptr, layout);
+        }
+    }
+
+    // return an allocation from quarantine to inner allocator
+    unsafe fn release(&self, ptr: *mut u8, layout: Layout) {
+        if !is_filled(ptr, layout.size(), POISON) {
+            report("freed memory overwritten (use after free)", ptr, &layout);
+        }
+        let base = ptr.sub(Self::front_size(&layout));
+        self.inner.dealloc(base, Self::inner_layout(&layout).unwrap());
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let inner_layout = match Self::inner_layout(&layout) {
+            Some(inner_layout) => inner_layout,
+            None => return ptr::null_mut()
+        };
+        let base = self.inner.alloc(inner_layout);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(Self::front_size(&layout));
+        Self::header(ptr).write(Header {
+            state: ALLOCATED,
+            size: layout.size(),
+            align: layout.align()
+        });
+        ptr::write_bytes(ptr.sub(RED_ZONE_SIZE), CANARY, RED_ZONE_SIZE);
+        ptr::write_bytes(ptr.add(layout.size()), CANARY, RED_ZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        Self::check_allocation(ptr, &layout);
+
+        // poison the freed memory and mark it as freed
+        ptr::write_bytes(ptr, POISON, layout.size());
+        (*Self::header(ptr)).state = FREED;
+
+        // put the allocation in quarantine, and release the oldest one in quarantine
+        let evicted = {
+            let mut quarantine = self.quarantine.lock();
+            let (entries, next) = &mut *quarantine;
+            let evicted = entries[*next].replace((ptr as usize, layout));
+            *next = (*next + 1) % QUARANTINE_SIZE;
+            evicted
+        };  // release the quarantine lock before calling the inner allocator
+        if let Some((old_ptr, old_layout)) = evicted {
+            self.release(old_ptr as *mut u8, old_layout);
+        }
+    }
+}
+
+
+// check all bytes in memory region have value
+unsafe fn is_filled(start: *const u8, size: usize, value: u8) -> bool {
+    (0..size).all(|offset| start.add(offset).read_volatile() == value)
+}
+
+// report a heap error to serial and stop the kernel
+fn report(error: &str, ptr: *mut u8, layout: &Layout) -> ! {
+    serial_println!("HEAP ERROR: {} at {:p} (size {}, align {})", error, ptr, layout.size(), layout.align());
+    panic!("heap error: {} at {:p}", error, ptr);
+}
+
+
+// test the red zones of an allocation and the poisoning of freed memory, on a local heap
+#[test_case]
+fn test_red_zones_and_poison() {
+    use super::{bump_allocator::BumpAllocator, Locked};
+
+    let mut arena = [0u64; 128];
+    let inner = Locked::new(BumpAllocator::new());
+    unsafe { inner.lock().init(arena.as_mut_ptr() as usize, mem::size_of_val(&arena)) };
+    let allocator = DebugAllocator::new(inner);
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    assert_eq!(DebugAllocator::<Locked<BumpAllocator>>::inner_layout(&layout).unwrap().size(),
+        HEADER_OFFSET + 24 + RED_ZONE_SIZE);
+    unsafe {
+        let ptr = allocator.alloc(layout);
+        assert_eq!(ptr as usize % 8, 0);
+        assert!(is_filled(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE, CANARY));
+        assert!(is_filled(ptr.add(24), RED_ZONE_SIZE, CANARY));
+        assert_eq!((*DebugAllocator::<Locked<BumpAllocator>>::header(ptr)).state, ALLOCATED);
+
+        ptr.write_bytes(0x11, 24);
+        allocator.dealloc(ptr, layout);
+        // the memory stays in quarantine, poisoned
+        assert!(is_filled(ptr, 24, POISON));
+        assert_eq!((*DebugAllocator::<Locked<BumpAllocator>>::header(ptr)).state, FREED);
+    }
+}
@@ -1,16 +1,17 @@
 use alloc::alloc::Layout;
-use super::Locked;
+use super::{realloc_by_copy, Locked};
 use core::mem;
 use alloc::alloc::GlobalAlloc;
 
-use crate::allocator::LinkedListAllocator;
+use super::linked_list::LinkedListAllocator;
+use super::stats::FreeRegionStats;
 
 /*
 The block sizes we include for fixed-block allocator
 The block sizes need to be power of two as required by alignment
 For allocations larger than 2048 bytes, we fall back to linkedlist allocator
 */
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
 // find the block with the leaset size required by the layout
 fn list_index(layout: &Layout) -> Option<usize> {
@@ -45,6 +46,47 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size);
     }
 
+    // count the free blocks in the linkedlist of each block size
+    pub fn free_block_counts(&self) -> [usize; BLOCK_SIZES.len()] {
+        let mut counts = [0; BLOCK_SIZES.len()];
+        for (count, head) in counts.iter_mut().zip(self.list_heads.iter()) {
+            let mut current = head.as_deref();
+            while let Some(node) = current {
+                *count += 1;
+                current = node.next.as_deref();
+            }
+        }
+        counts
+    }
+
+    // the free regions of the fallback allocator
+    pub fn fallback_region_stats(&self) -> FreeRegionStats {
+        self.fallback_allocator.free_region_stats()
+    }
+
+    // add a new memory region to the heap, e.g. after the heap has grown
+    // unsafe: the caller needs to ensure the memory region is valid and unused
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.fallback_allocator.extend(addr, size);
+    }
+
+    // return all free blocks to the fallback allocator, return the number of released blocks
+    // this makes the memory of small blocks available to other sizes
+    pub fn shrink(&mut self) -> usize {
+        let mut released = 0;
+        for (index, head) in self.list_heads.iter_mut().enumerate() {
+            let layout = Layout::from_size_align(BLOCK_SIZES[index], BLOCK_SIZES[index]).unwrap();
+            while let Some(node) = head.take() {
+                *head = node.next.take();
+                unsafe {
+                    self.fallback_allocator.deallocate(node as *mut ListNode as *mut u8, layout);
+                }
+                released += 1;
+            }
+        }
+        released
+    }
+
     // allocate with fallback allocator
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         unsafe { self.fallback_allocator.allocate(layout) }
@@ -107,5 +149,21 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             }
         }
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (list_index(&layout), list_index(&new_layout)) {
+            // the block is large enough for the new size
+            (Some(index), Some(new_index)) if index == new_index => return ptr,
+            // large allocations are resized in place by the fallback allocator
+            (None, None) => {
+                if self.lock().fallback_allocator.resize_in_place(ptr, layout, new_size) {
+                    return ptr;
+                }
+            },
+            _ => {}
+        }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
 }
 
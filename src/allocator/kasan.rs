@@ -0,0 +1,351 @@
+/*
+Kernel address sanitizer (cargo feature kasan)
+
+Every 8 bytes (a granule) of heap memory have one shadow byte that records which of the
+bytes can be accessed:
+
+    0               all 8 bytes are allocated
+    1 to 7          only the first 1 to 7 bytes are allocated (the end of an allocation)
+    UNALLOCATED     the heap memory was never allocated, or is padding of the heap allocator
+    FREED           the memory was freed
+
+The shadow memory is an area in memory::vma, and the shadow of heap memory is mapped
+whenever the heap grows. KernelAllocator unpoisons an allocation (marks it accessible)
+on alloc and poisons it on dealloc. Allocations are at least 8 bytes aligned, so two
+allocations never share a granule.
+
+Accesses are not instrumented by the compiler, code that may access invalid memory calls
+check_read and check_write explicitly. An invalid access is reported to serial with the
+allocation containing (or preceding) the address, and the kernel panics.
+
+The allocator records the return addresses of the last ALLOC_RECORDS allocations as their
+allocation site, by walking the frame pointers. The target does not enable frame pointers,
+build with RUSTFLAGS="-C force-frame-pointers=yes" for the sites to be meaningful.
+The walk only follows frame pointers inside the current stack: the boot stack (its mapped
+memory is found by init) or a kernel stack (see memory::stack). The addresses can be
+resolved with addr2line
+*/
+use alloc::alloc::Layout;
+use core::arch::asm;
+use core::panic::Location;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+
+use crate::memory::{page_table, stack, MemoryManager};
+use crate::memory::vma::{self, AreaKind};
+use crate::{serial_print, serial_println};
+use super::{align_up, HEAP_AREA_SIZE, HEAP_END, HEAP_START};
+
+// each shadow byte covers a granule of 8 heap bytes
+const GRANULE_SIZE: usize = 8;
+pub const UNALLOCATED: u8 = 0xFC;
+pub const FREED: u8 = 0xFB;
+
+// the number of allocations recorded for reports, and the return addresses of each
+const ALLOC_RECORDS: usize = 256;
+const SITE_FRAMES: usize = 6;
+// the largest stack frame followed when walking frame pointers
+const MAX_FRAME_SIZE: u64 = 64 * 1024;
+
+// the start of the shadow memory area (0 before init), and the end of its mapped part
+static SHADOW_START: AtomicU64 = AtomicU64::new(0);
+static SHADOW_MAPPED_END: AtomicU64 = AtomicU64::new(0);
+// the number of allocations so far, identifies allocations in reports
+static NEXT_ALLOC_ID: AtomicUsize = AtomicUsize::new(0);
+// the mapped memory around the boot stack (0 before init)
+static BOOT_STACK_START: AtomicU64 = AtomicU64::new(0);
+static BOOT_STACK_END: AtomicU64 = AtomicU64::new(0);
+
+
+// an allocation recorded for reports
+#[derive(Clone, Copy)]
+struct AllocRecord {
+    id: usize,
+    start: usize,
+    size: usize,
+    freed: bool,
+    site: [u64; SITE_FRAMES]    // return addresses, 0 if the stack is shorter
+}
+
+// the recorded allocations (a ring buffer) and the next entry to replace
+static RECORDS: Mutex<([Option<AllocRecord>; ALLOC_RECORDS], usize)> = Mutex::new(([None; ALLOC_RECORDS], 0));
+
+
+/*
+Reserve the shadow memory area, the shadow is mapped as the heap grows, and find the
+boot stack for allocation_site
+This is called by allocator::init_heap before the heap is mapped, on the boot stack
+*/
+pub fn init() {
+    let area = vma::reserve((HEAP_AREA_SIZE / GRANULE_SIZE) as u64, AreaKind::Other, "kasan shadow")
+        .expect("no virtual memory for kasan shadow");
+    SHADOW_MAPPED_END.store(area.start.as_u64(), Ordering::SeqCst);
+    SHADOW_START.store(area.start.as_u64(), Ordering::SeqCst);
+
+    if let Some((start, end)) = page_table::mapped_range(stack_pointer()) {
+        BOOT_STACK_START.store(start.as_u64(), Ordering::SeqCst);
+        BOOT_STACK_END.store(end.as_u64(), Ordering::SeqCst);
+    }
+}
+
+/*
+Map the shadow of the new heap memory from start to start + size, and mark it unallocated
+The heap grows at its end, so only the shadow after the mapped part needs new pages
+*/
+pub(super) fn map_shadow(memory_manager: &mut MemoryManager, start: usize, size: usize)
+    -> Result<(), MapToError<Size4KiB>>
+{
+    let shadow_start = shadow_addr(start);
+    let shadow_end = shadow_addr(start + size);
+    let mapped_end = SHADOW_MAPPED_END.load(Ordering::SeqCst) as usize;
+    let new_end = align_up(shadow_end, Size4KiB::SIZE as usize);
+    if new_end > mapped_end {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        memory_manager.map_range(VirtAddr::new(mapped_end as u64), (new_end - mapped_end) as u64, flags)?;
+        SHADOW_MAPPED_END.store(new_end as u64, Ordering::SeqCst);
+    }
+    unsafe { ptr::write_bytes(shadow_start as *mut u8, UNALLOCATED, shadow_end - shadow_start) };
+    Ok(())
+}
+
+// the address of the shadow byte of addr (in the heap)
+fn shadow_addr(addr: usize) -> usize {
+    SHADOW_START.load(Ordering::Relaxed) as usize + (addr - HEAP_START) / GRANULE_SIZE
+}
+
+fn shadow(addr: usize) -> u8 {
+    unsafe { (shadow_addr(addr) as *const u8).read_volatile() }
+}
+
+// check whether addr is in heap memory that has shadow
+fn has_shadow(addr: usize) -> bool {
+    SHADOW_START.load(Ordering::Relaxed) != 0
+        && HEAP_START <= addr
+        && addr < HEAP_END.load(Ordering::Relaxed)
+}
+
+
+// the layout used for an allocation, so allocations do not share granules
+pub(super) fn layout(layout: Layout) -> Layout {
+    layout.align_to(GRANULE_SIZE).expect("invalid allocation layout")
+}
+
+/*
+Mark size bytes starting at ptr (8 bytes aligned) as accessible
+unsafe: the memory must be heap memory that has shadow
+*/
+pub unsafe fn unpoison(ptr: *mut u8, size: usize) {
+    let start = ptr as usize;
+    let full_granules = size / GRANULE_SIZE;
+    ptr::write_bytes(shadow_addr(start) as *mut u8, 0, full_granules);
+    if size % GRANULE_SIZE != 0 {
+        let last = (shadow_addr(start) + full_granules) as *mut u8;
+        last.write_volatile((size % GRANULE_SIZE) as u8);
+    }
+}
+
+/*
+Mark size bytes starting at ptr (8 bytes aligned) as inaccessible with value
+(UNALLOCATED or FREED), the last granule is poisoned completely
+unsafe: the memory must be heap memory that has shadow
+*/
+pub unsafe fn poison(ptr: *mut u8, size: usize, value: u8) {
+    let start = ptr as usize;
+    let granules = (size + GRANULE_SIZE - 1) / GRANULE_SIZE;
+    ptr::write_bytes(shadow_addr(start) as *mut u8, value, granules);
+}
+
+
+// called by KernelAllocator after a successful allocation
+pub(super) unsafe fn on_alloc(ptr: *mut u8, layout: Layout) {
+    if !has_shadow(ptr as usize) {
+        return;
+    }
+    unpoison(ptr, layout.size());
+    let record = AllocRecord {
+        id: NEXT_ALLOC_ID.fetch_add(1, Ordering::Relaxed),
+        start: ptr as usize,
+        size: layout.size(),
+        freed: false,
+        site: allocation_site()
+    };
+    let mut records = RECORDS.lock();
+    let (entries, next) = &mut *records;
+    entries[*next] = Some(record);
+    *next = (*next + 1) % ALLOC_RECORDS;
+}
+
+// called by KernelAllocator before the allocation at ptr is freed
+pub(super) unsafe fn on_dealloc(ptr: *mut u8, layout: Layout) {
+    let addr = ptr as usize;
+    // memory outside the heap has no shadow, it was not allocated by the heap allocator
+    if !has_shadow(addr) {
+        serial_println!("KASAN: invalid free at {:#x}: outside the heap", addr);
+        return;
+    }
+    match shadow(addr) {
+        FREED => report("double free", addr, layout.size(), "free", None),
+        UNALLOCATED => report("invalid free", addr, layout.size(), "free", None),
+        _ => {}
+    }
+    poison(ptr, layout.size(), FREED);
+
+    let mut records = RECORDS.lock();
+    let record = records.0.iter_mut()
+        .flatten()
+        .filter(|record| record.start == addr && !record.freed)
+        .max_by_key(|record| record.id);
+    if let Some(record) = record {
+        record.freed = true;
+    }
+}
+
+// called by KernelAllocator after an allocation moved or was resized by realloc
+pub(super) unsafe fn on_realloc(ptr: *mut u8, layout: Layout, new_ptr: *mut u8, new_size: usize) {
+    on_dealloc(ptr, layout);
+    on_alloc(new_ptr, Layout::from_size_align_unchecked(new_size, layout.align()));
+}
+
+#[inline(always)]
+fn stack_pointer() -> VirtAddr {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    VirtAddr::new(rsp)
+}
+
+// the current stack pointer and the top of its stack, None on an unknown stack
+fn current_stack() -> Option<(u64, u64)> {
+    let rsp = stack_pointer();
+    let (boot_start, boot_end) = (BOOT_STACK_START.load(Ordering::Relaxed), BOOT_STACK_END.load(Ordering::Relaxed));
+    if boot_start <= rsp.as_u64() && rsp.as_u64() < boot_end {
+        return Some((rsp.as_u64(), boot_end));
+    }
+    stack::stack_bounds(rsp).map(|(_, top)| (rsp.as_u64(), top.as_u64()))
+}
+
+// the return addresses of the allocator call, without the frames of the allocator itself
+#[inline(always)]
+fn allocation_site() -> [u64; SITE_FRAMES] {
+    let mut site = [0; SITE_FRAMES];
+    // without known stack bounds, following rbp could read unmapped memory
+    let (bottom, top) = match current_stack() {
+        Some(bounds) => bounds,
+        None => return site
+    };
+    let mut frame: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) frame, options(nomem, nostack, preserves_flags)) };
+    // skip the return addresses into KernelAllocator and __rust_alloc
+    let skip = 2;
+    for i in 0..skip + SITE_FRAMES {
+        // the saved rbp and the return address must be on the current stack
+        if frame % 8 != 0 || frame < bottom || top.saturating_sub(16) < frame {
+            break;
+        }
+        let (next, return_addr) = unsafe { (*(frame as *const u64), *((frame + 8) as *const u64)) };
+        if i >= skip {
+            site[i - skip] = return_addr;
+        }
+        // the caller frame is above this frame on the stack
+        if next <= frame || next - frame > MAX_FRAME_SIZE {
+            break;
+        }
+        frame = next;
+    }
+    site
+}
+
+
+/*
+Check that size bytes starting at addr can be read
+Addresses outside the heap are not checked
+*/
+#[track_caller]
+#[inline]
+pub fn check_read(addr: *const u8, size: usize) {
+    check(addr as usize, size, false, Location::caller());
+}
+
+// check that size bytes starting at addr can be written
+#[track_caller]
+#[inline]
+pub fn check_write(addr: *mut u8, size: usize) {
+    check(addr as usize, size, true, Location::caller());
+}
+
+fn check(addr: usize, size: usize, write: bool, location: &Location) {
+    if size == 0 || !has_shadow(addr) {
+        return;
+    }
+    if let Some(invalid) = first_invalid(addr, size) {
+        let error = match shadow(invalid) {
+            FREED => "use after free",
+            UNALLOCATED => "access to unallocated heap memory",
+            _ => "heap out of bounds"
+        };
+        report(error, invalid, size, if write { "write" } else { "read" }, Some(location));
+    }
+}
+
+// check whether size bytes starting at addr can be accessed
+pub fn is_accessible(addr: *const u8, size: usize) -> bool {
+    !has_shadow(addr as usize) || first_invalid(addr as usize, size).is_none()
+}
+
+// the first byte from addr to addr + size that cannot be accessed (in heap memory)
+fn first_invalid(addr: usize, size: usize) -> Option<usize> {
+    let end = addr.checked_add(size)?.min(HEAP_END.load(Ordering::Relaxed));
+    let mut granule = addr - addr % GRANULE_SIZE;
+    while granule < end {
+        let accessible = match shadow(granule) {
+            0 => GRANULE_SIZE,
+            value if (value as usize) < GRANULE_SIZE => value as usize,
+            _ => 0
+        };
+        // the accessed bytes in this granule
+        let first = addr.max(granule);
+        let last = end.min(granule + GRANULE_SIZE) - 1;
+        if last - granule >= accessible {
+            return Some(first.max(granule + accessible));
+        }
+        granule += GRANULE_SIZE;
+    }
+    None
+}
+
+
+// report an invalid access (read, write or free) of size bytes at addr to serial and stop the kernel
+// location is the caller of the check, if known
+fn report(error: &str, addr: usize, size: usize, access: &str, location: Option<&Location>) -> ! {
+    serial_print!("KASAN: {} at {:#x}: {} of {} bytes", error, addr, access, size);
+    if let Some(location) = location {
+        serial_print!(" at {}", location);
+    }
+    serial_println!();
+
+    // the allocation containing addr, or the closest allocation before it
+    let record = RECORDS.lock().0.iter()
+        .flatten()
+        .filter(|record| record.start <= addr)
+        .max_by_key(|record| (record.start, record.id))
+        .copied();
+    match record {
+        Some(record) => {
+            serial_println!("allocation #{} at {:#x}-{:#x} ({} bytes){}",
+                record.id, record.start, record.start + record.size, record.size,
+                if record.freed { ", freed" } else { "" });
+            serial_println!("allocated by:");
+            for return_addr in record.site.iter().take_while(|&&addr| addr != 0) {
+                serial_println!("    {:#x}", return_addr);
+            }
+        },
+        None => {
+            serial_println!("no recorded allocation before {:#x}", addr);
+        }
+    }
+    panic!("KASAN: {} at {:#x}", error, addr);
+}
@@ -1,4 +1,5 @@
-use super::{align_up, Locked};
+use super::{align_up, realloc_by_copy, Locked};
+use super::stats::FreeRegionStats;
 use core::{mem, ptr, fmt};
 use alloc::alloc::{GlobalAlloc, Layout};
 
@@ -35,97 +36,134 @@ impl fmt::Display for ListNode {
 }
 
 
+/*
+The placement strategy used to choose a free region for an allocation
+    FirstFit: the first large enough region, the fastest strategy
+    NextFit: the first large enough region after the previous allocation,
+             which spreads allocations instead of crowding the start of heap
+    BestFit: the smallest large enough region, which keeps large regions
+             available for large allocations, but always searches the whole list
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitStrategy {
+    FirstFit,
+    NextFit,
+    BestFit
+}
+
+
 // the linkedlist allocator
+// the free regions are sorted by address, and adjacent free regions are always merged
 pub struct LinkedListAllocator {
-    head: ListNode
+    head: ListNode,
+    strategy: FitStrategy,
+    next_fit: usize     // the end address of the previous allocation, used by NextFit
 }
 
 impl LinkedListAllocator {
-    // create an empty LinkedListAllocator
+    // create an empty LinkedListAllocator using first fit
     pub const fn new() -> Self {
+        Self::with_strategy(FitStrategy::FirstFit)
+    }
+
+    // create an empty LinkedListAllocator with the given placement strategy
+    pub const fn with_strategy(strategy: FitStrategy) -> Self {
         Self {
             // head is a placeholder and does not store heap memory
             // head.next points to the first node that stores heap memory
-            head: ListNode::new(0)
+            head: ListNode::new(0),
+            strategy,
+            next_fit: 0
         }
     }
 
+    pub fn strategy(&self) -> FitStrategy {
+        self.strategy
+    }
+
     // initialize the allocator with the given heap bound
     // unsafe: the caller needs to ensure the starting address and size are valid
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
     }
 
-    // apppend a free region with given starting address and size to the linkedlist
+    // add a new memory region to the heap, e.g. after the heap has grown
+    // unsafe: the caller needs to ensure the memory region is valid and unused
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.add_free_region(addr, size);
+    }
+
+    /*
+    Insert a free region with given starting address and size to the linkedlist
+
+    The list is sorted by address, so the region is inserted after the last region
+    starting before it. If the region is adjacent to its previous or next region,
+    they are merged immediately, so the list never contains adjacent regions
+    */
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure the memory is aligned 
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         // ensure the memory is large enough to hold the linkedlist
         assert!(size >= mem::size_of::<ListNode>());
 
-        // the new node
-        let mut new_node = ListNode::new(size); // the node is now on stack
         // find the proper location of node in linkedlist
-        
-        // add node to the head of list
         let mut current = &mut self.head;
+        let mut is_head = true;     // head is a placeholder and can never be merged
+        while current.next.as_ref().map_or(false, |next| next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+            is_head = false;
+        }
+
+        // a region overlapping a free region is freed twice
+        assert!(is_head || current.end_addr() <= addr, "freed region overlaps free region");
+        if let Some(ref next) = current.next {
+            assert!(addr + size <= next.start_addr(), "freed region overlaps free region");
+        }
 
-        if current.next.is_none() ||
-            addr < current.next.as_ref().unwrap().start_addr() {    // the head is None or new node has smaller start address than first node
-            new_node.next = current.next.take();
+        if !is_head && current.end_addr() == addr {
+            // merge with the previous region
+            current.size += size;
         } else {
-            // traverse the linkedlist
-            while let Some(ref mut next) = current.next {
-                if next.start_addr() >= addr {  
-                    break;
-                }
-                current = current.next.as_mut().unwrap()
-            }
-            // assign the node's next node
+            // write the new node to memory and connect it with the previous node
             // we can only assign its previous node when the node is written to memory (having a 'static lifecycle)
-            new_node.next = current.next.take();
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(ListNode {
+                size,
+                next: current.next.take()
+            });
+            current.next = Some(&mut *node_ptr);
+            current = current.next.as_mut().unwrap();
         }
 
-
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(new_node);   // write the new node in memory
-        current.next = Some(&mut *node_ptr);    // connect the node with the previous node
-
+        // merge with the next region
+        let end_addr = current.end_addr();
+        if current.next.as_ref().map_or(false, |next| next.start_addr() == end_addr) {
+            let next = current.next.take().unwrap();
+            current.size += next.size;
+            current.next = next.next.take();
+        }
     }
 
-    // merge consecutive free memory regions into larger regions
-    fn merge_region(&mut self) {
-        let mut current = &mut self.head;
-
-        while let Some(ref mut node) = current.next {
-            // get the start and end address of current node (prevent borrowing issue)
-            let start_addr = node.start_addr();
-            let end_addr = node.end_addr();
-            // check whether it is adjacent to the next node
-            if let Some(ref mut next) = node.next {
-                if end_addr == next.start_addr() {
-                    // combine two memory regions
-                    node.size = next.end_addr() - start_addr;
-                    node.next = next.next.take();
-                }
-            }
-
-            current = current.next.as_mut().unwrap();
-        }
+    // iterate over the free regions in address order
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let node = current?;
+            current = node.next.as_deref();
+            Some(node)
+        })
     }
 
-    // find a large enough unused heap region 
-    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+    // remove and return the region starting at addr from free list
+    fn take_region(&mut self, addr: usize) -> Option<&'static mut ListNode> {
         let mut current = &mut self.head;
         // while let: repeatedly execute the code as long as the pattern matching is successful
         // equivalent pseudocode: while current.next == Some
         while let Some(ref mut region) = current.next {
-            // find a region that is large enough
-            if let Ok(alloc_start) = Self::alloc_from_region(&region, size, align) {
-                // remove and return the assigned node from free list
+            if region.start_addr() == addr {
+                // remove the node from free list
                 let next = region.next.take();
-                // return the region together with alloc_start address
-                let ret = Some((current.next.take().unwrap(), alloc_start));
+                let ret = current.next.take();
                 current.next = next;
                 return ret;
             } else {
@@ -134,12 +172,40 @@ impl LinkedListAllocator {
             }
         }
 
-        // there is no large enough memory region in heap
         None
     }
 
+    // find a large enough unused heap region with the placement strategy
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let fits = |region: &&ListNode| Self::alloc_from_region(region, size, align).is_ok();
+        let region = match self.strategy {
+            FitStrategy::FirstFit => self.regions().find(fits),
+            // search from the previous allocation, then wrap around to the start of heap
+            FitStrategy::NextFit => self.regions()
+                .filter(|region| region.end_addr() > self.next_fit)
+                .find(fits)
+                .or_else(|| self.regions().find(fits)),
+            FitStrategy::BestFit => self.regions()
+                .filter(fits)
+                .min_by_key(|region| region.size)
+        };
+
+        // there is no large enough memory region in heap
+        let region_addr = region?.start_addr();
+        let region = self.take_region(region_addr).unwrap();
+        let alloc_start = Self::alloc_from_region(region, size, align).unwrap();
+        // return the region together with alloc_start address
+        Some((region, alloc_start))
+    }
+
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        // the padding before the allocation need to be large enough to create a new ListNode
+        // otherwise, the padding is lost. Move the allocation further to leave enough space
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;   // check for intenger overflow
 
         // return error if the end address does not fit into the memory region
@@ -164,11 +230,17 @@ impl LinkedListAllocator {
         // find a node that contains a large enough region
         if let Some((region, alloc_start)) = self.find_region(size, align) {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
+            // return the padding before the allocation to free list
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
             // append a new node in free list to store remaining memory region in the allocation
-            let excess_size = region.end_addr() - alloc_end;
+            let excess_size = region_end - alloc_end;
             if excess_size > 0 {
                 self.add_free_region(alloc_end, excess_size);
             }
+            self.next_fit = alloc_end;
             alloc_start as *mut u8
         } else {
             // cannot find a memory region with appropriate size
@@ -179,10 +251,54 @@ impl LinkedListAllocator {
     // deallocate a heap memory region
     pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
-        // add the freed region to free list
+        // add the freed region to free list, it is merged with adjacent free regions
         self.add_free_region(ptr as usize, size);
-        // merge unused regions
-        self.merge_region();
+    }
+
+    /*
+    Resize an allocation without moving it, return false if it is not possible
+
+    Shrinking releases the end of the allocation. Growing takes memory from the
+    free region directly after the allocation, if there is one and it is large enough.
+    The remaining memory must be either empty or large enough to store a ListNode
+    */
+    pub unsafe fn resize_in_place(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> bool {
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+        let start = ptr as usize;
+
+        if new_size <= old_size {
+            let excess_size = old_size - new_size;
+            if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+                return false;
+            }
+            if excess_size > 0 {
+                self.add_free_region(start + new_size, excess_size);
+            }
+            return true;
+        }
+
+        // the free region after the allocation must be large enough for the extra size
+        let old_end = start + old_size;
+        let extra_size = new_size - old_size;
+        let next_size = match self.regions().find(|region| region.start_addr() == old_end) {
+            Some(region) => region.size,
+            None => return false
+        };
+        if next_size < extra_size {
+            return false;
+        }
+        let excess_size = next_size - extra_size;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return false;
+        }
+
+        self.take_region(old_end);
+        if excess_size > 0 {
+            self.add_free_region(start + new_size, excess_size);
+        }
+        true
     }
 
 
@@ -197,6 +313,18 @@ impl LinkedListAllocator {
     }
 
 
+    // collect the number and sizes of free regions
+    pub fn free_region_stats(&self) -> FreeRegionStats {
+        let mut stats = FreeRegionStats::default();
+        for node in self.regions() {
+            stats.regions += 1;
+            stats.free_bytes += node.size;
+            stats.largest = stats.largest.max(node.size);
+        }
+        stats
+    }
+
+
     // test method: print the linked list    
     fn print_linkedlist(&self) {
         let mut current = &self.head;
@@ -217,4 +345,11 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         self.lock().deallocate(ptr, layout)
     }
 
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if self.lock().resize_in_place(ptr, layout, new_size) {
+            return ptr;
+        }
+        // move the allocation if it cannot be resized in place
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
 }
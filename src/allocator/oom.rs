@@ -0,0 +1,59 @@
+/*
+Out-of-memory handling
+
+When an allocation fails even after the heap has grown to its limit, KernelAllocator
+asks the heap allocator to release its caches and calls the registered OOM handlers,
+then retries the allocation. Each OOM handler tries to free memory (e.g. drop caches
+of a subsystem) and returns whether it freed anything.
+
+If the allocation still fails, a report of the heap state is printed to serial before
+the allocation returns null (and the kernel panics in the alloc error handler)
+*/
+use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::serial_println;
+
+// an OOM handler, return true if it freed memory so the allocation should be retried
+pub type OomHandler = fn(Layout) -> bool;
+
+const MAX_OOM_HANDLERS: usize = 8;
+
+static OOM_HANDLERS: Mutex<[Option<OomHandler>; MAX_OOM_HANDLERS]> = Mutex::new([None; MAX_OOM_HANDLERS]);
+
+// prevent calling OOM handlers recursively when an OOM handler allocates memory
+static IN_OOM: AtomicBool = AtomicBool::new(false);
+
+// register a handler that is called when the heap is out of memory
+pub fn register_oom_handler(handler: OomHandler) {
+    let mut handlers = OOM_HANDLERS.lock();
+    let slot = handlers.iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many OOM handlers");
+    *slot = Some(handler);
+}
+
+// call all OOM handlers, return true if any of them freed memory
+pub(super) fn reclaim(layout: Layout) -> bool {
+    if IN_OOM.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+
+    // copy the handlers, so the lock is not held when handlers run
+    let handlers = *OOM_HANDLERS.lock();
+    let mut freed = false;
+    for handler in handlers.iter().flatten() {
+        freed |= handler(layout);
+    }
+
+    IN_OOM.store(false, Ordering::SeqCst);
+    freed
+}
+
+// print the state of heap when an allocation fails
+pub(super) fn report(layout: Layout) {
+    serial_println!("OUT OF MEMORY: allocation of {} bytes (align {}) failed", layout.size(), layout.align());
+    serial_println!("heap limit: {} bytes, heap allocator: {}", super::heap_limit(), super::ALLOCATOR_NAME);
+    super::print_stats();
+}
@@ -0,0 +1,485 @@
+/*
+Implementation of a slab allocator
+
+A slab cache manages objects of one fixed size. It takes whole pages (slabs) from
+a page provider and carves each slab into objects of that size:
+
+    | Slab header | object | object | ... | object | unused |
+    ^ page start (aligned to SLAB_SIZE)
+
+Free objects of a slab are kept in a linkedlist inside the slab. Since slabs are
+aligned to SLAB_SIZE, the slab of an object is found by rounding its address down.
+
+Slabs with free objects are in the partial list, slabs without free objects are in
+the full list. Slabs that become empty are returned to the page provider.
+
+Unlike the fixed-size block allocator, the object size does not need to be a power
+of two, so objects waste less memory, and the memory of freed objects is released
+once their slab is empty.
+
+There are two ways to use slab caches:
+- ObjectCache<T>: a named cache for objects of a type, e.g. a cache for Tasks
+- SlabAllocator: a heap allocator using a set of slab caches for small allocations
+  and a linkedlist allocator for large ones (cargo feature alloc-slab)
+*/
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{fmt, mem, ptr};
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+use super::{realloc_by_copy, Locked};
+use super::linked_list::LinkedListAllocator;
+use super::stats::FreeRegionStats;
+
+// the size and alignment of a slab
+pub const SLAB_SIZE: usize = 4096;
+
+
+// the source of slab memory
+pub trait PageProvider {
+    // allocate SLAB_SIZE bytes aligned to SLAB_SIZE, return null if out of memory
+    unsafe fn alloc_page(&mut self) -> *mut u8;
+    // release a page returned by alloc_page
+    unsafe fn free_page(&mut self, page: *mut u8);
+}
+
+// the page layout requested from page providers
+fn page_layout() -> Layout {
+    Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap()
+}
+
+// take slab pages from the global heap allocator
+pub struct HeapPages;
+
+impl PageProvider for HeapPages {
+    unsafe fn alloc_page(&mut self) -> *mut u8 {
+        alloc::alloc::alloc(page_layout())
+    }
+
+    unsafe fn free_page(&mut self, page: *mut u8) {
+        alloc::alloc::dealloc(page, page_layout())
+    }
+}
+
+// take slab pages from a linkedlist allocator (the fallback of SlabAllocator)
+impl PageProvider for LinkedListAllocator {
+    unsafe fn alloc_page(&mut self) -> *mut u8 {
+        self.allocate(page_layout())
+    }
+
+    unsafe fn free_page(&mut self, page: *mut u8) {
+        self.deallocate(page, page_layout())
+    }
+}
+
+
+// a free object, the node of free list in a slab
+struct FreeObject {
+    next: Option<&'static mut FreeObject>
+}
+
+// the header at the start of each slab
+struct Slab {
+    next: Option<&'static mut Slab>,   // the next slab in partial or full list
+    free_objects: Option<&'static mut FreeObject>,
+    in_use: usize   // number of allocated objects
+}
+
+impl Slab {
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+
+// the statistics of a slab cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats {
+    pub slabs: usize,   // number of slabs
+    pub objects_in_use: usize,
+    pub capacity: usize     // number of objects that fit into all slabs
+}
+
+
+// a cache of objects with the same size
+pub struct SlabCache {
+    name: &'static str,
+    object_size: usize,
+    object_align: usize,
+    first_object: usize,    // the offset of the first object from slab start
+    objects_per_slab: usize,
+    partial: Option<&'static mut Slab>,    // slabs with at least one free object
+    full: Option<&'static mut Slab>    // slabs without free objects
+}
+
+impl SlabCache {
+    // create an empty slab cache for objects with given size and alignment
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        // each object needs to be able to store a FreeObject when it is free
+        let align = if align < mem::align_of::<FreeObject>() { mem::align_of::<FreeObject>() } else { align };
+        let size = if size < mem::size_of::<FreeObject>() { mem::size_of::<FreeObject>() } else { size };
+        let object_size = (size + align - 1) & !(align - 1);
+        let first_object = (mem::size_of::<Slab>() + align - 1) & !(align - 1);
+        assert!(first_object + object_size <= SLAB_SIZE, "object too large for slab");
+
+        SlabCache {
+            name,
+            object_size,
+            object_align: align,
+            first_object,
+            objects_per_slab: (SLAB_SIZE - first_object) / object_size,
+            partial: None,
+            full: None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // the size of each object, including padding
+    pub fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    pub fn object_align(&self) -> usize {
+        self.object_align
+    }
+
+    // allocate an object, take a new slab from provider if all slabs are full
+    pub unsafe fn allocate(&mut self, provider: &mut impl PageProvider) -> *mut u8 {
+        if self.partial.is_none() {
+            let page = provider.alloc_page();
+            if page.is_null() {
+                return ptr::null_mut();
+            }
+            self.partial = Some(self.init_slab(page as usize));
+        }
+
+        // take the first free object of the first partial slab
+        let slab = self.partial.as_mut().unwrap();
+        let object = slab.free_objects.take().unwrap();
+        slab.free_objects = object.next.take();
+        slab.in_use += 1;
+
+        // move the slab to full list if it has no free objects
+        if slab.free_objects.is_none() {
+            let slab = self.partial.take().unwrap();
+            self.partial = slab.next.take();
+            slab.next = self.full.take();
+            self.full = Some(slab);
+        }
+
+        object as *mut FreeObject as *mut u8
+    }
+
+    // free an object allocated by this cache, and release its slab if it becomes empty
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, provider: &mut impl PageProvider) {
+        let slab_addr = ptr as usize & !(SLAB_SIZE - 1);
+        let slab_ptr = slab_addr as *mut Slab;
+
+        // a full slab moves back to partial list since it has a free object now
+        if (*slab_ptr).free_objects.is_none() {
+            let slab = Self::unlink(&mut self.full, slab_addr).expect("object not allocated by this cache");
+            slab.next = self.partial.take();
+            self.partial = Some(slab);
+        }
+
+        // push the object to the free list of its slab
+        let object_ptr = ptr as *mut FreeObject;
+        object_ptr.write(FreeObject {
+            next: (*slab_ptr).free_objects.take()
+        });
+        (*slab_ptr).free_objects = Some(&mut *object_ptr);
+        (*slab_ptr).in_use -= 1;
+
+        // release the empty slab, but keep it if it is the only slab with free objects
+        // so allocating and freeing a single object does not request a page every time
+        if (*slab_ptr).in_use == 0 {
+            let only_slab = self.partial.as_ref()
+                .map_or(false, |head| head.start_addr() == slab_addr && head.next.is_none());
+            if !only_slab {
+                Self::unlink(&mut self.partial, slab_addr);
+                provider.free_page(slab_ptr as *mut u8);
+            }
+        }
+    }
+
+    // release all empty slabs to provider, return the number of released slabs
+    pub unsafe fn shrink(&mut self, provider: &mut impl PageProvider) -> usize {
+        let mut released = 0;
+        let mut current = &mut self.partial;
+        // walk the links of the partial list, an empty slab is unlinked by replacing the
+        // link to it with its next link, so current then already points to the next slab
+        while current.is_some() {
+            if current.as_ref().unwrap().in_use == 0 {
+                let slab = current.take().unwrap();
+                *current = slab.next.take();
+                provider.free_page(slab as *mut Slab as *mut u8);
+                released += 1;
+            } else {
+                current = &mut current.as_mut().unwrap().next;
+            }
+        }
+        released
+    }
+
+    // count slabs and objects in this cache
+    pub fn stats(&self) -> SlabCacheStats {
+        let mut stats = SlabCacheStats { slabs: 0, objects_in_use: 0, capacity: 0 };
+        for list in [&self.partial, &self.full] {
+            let mut current = list.as_deref();
+            while let Some(slab) = current {
+                stats.slabs += 1;
+                stats.objects_in_use += slab.in_use;
+                stats.capacity += self.objects_per_slab;
+                current = slab.next.as_deref();
+            }
+        }
+        stats
+    }
+
+    // write the slab header to a new page and put all objects in its free list
+    unsafe fn init_slab(&self, page: usize) -> &'static mut Slab {
+        assert_eq!(page % SLAB_SIZE, 0, "slab page is not aligned");
+
+        // push the objects in reverse order, so objects are allocated from low to high address
+        let mut free_objects = None;
+        for index in (0..self.objects_per_slab).rev() {
+            let object_ptr = (page + self.first_object + index * self.object_size) as *mut FreeObject;
+            object_ptr.write(FreeObject { next: free_objects });
+            free_objects = Some(&mut *object_ptr);
+        }
+
+        let slab_ptr = page as *mut Slab;
+        slab_ptr.write(Slab {
+            next: None,
+            free_objects,
+            in_use: 0
+        });
+        &mut *slab_ptr
+    }
+
+    // remove the slab at slab_addr from list
+    fn unlink(list: &mut Option<&'static mut Slab>, slab_addr: usize) -> Option<&'static mut Slab> {
+        let mut current = list;
+        while current.is_some() {
+            if current.as_ref().unwrap().start_addr() == slab_addr {
+                let slab = current.take().unwrap();
+                *current = slab.next.take();
+                return Some(slab);
+            }
+            current = &mut current.as_mut().unwrap().next;
+        }
+        None
+    }
+}
+
+impl fmt::Display for SlabCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = self.stats();
+        write!(f, "{}: object size {}, slabs {}, objects {}/{}",
+            self.name, self.object_size, stats.slabs, stats.objects_in_use, stats.capacity)
+    }
+}
+
+
+/*
+A named cache for objects of type T
+
+Slabs are taken from the global heap. Objects are allocated with alloc, which
+returns a CacheBox that frees the object when dropped, e.g.
+
+    static TASK_CACHE: ObjectCache<Task> = ObjectCache::new("task");
+    let task = TASK_CACHE.alloc(Task::new(example_task()));
+*/
+pub struct ObjectCache<T> {
+    cache: Locked<SlabCache>,
+    _type: PhantomData<fn() -> T>
+}
+
+impl<T> ObjectCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        ObjectCache {
+            cache: Locked::new(SlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>())),
+            _type: PhantomData
+        }
+    }
+
+    // move value into an object of this cache, return None if out of memory
+    pub fn try_alloc(&'static self, value: T) -> Option<CacheBox<T>> {
+        let ptr = unsafe { self.cache.lock().allocate(&mut HeapPages) } as *mut T;
+        let ptr = NonNull::new(ptr)?;
+        unsafe { ptr.as_ptr().write(value) };
+        Some(CacheBox { ptr, cache: self })
+    }
+
+    // move value into an object of this cache, panic if out of memory
+    pub fn alloc(&'static self, value: T) -> CacheBox<T> {
+        match self.try_alloc(value) {
+            Some(object) => object,
+            None => panic!("object cache {} out of memory", self.cache.lock().name())
+        }
+    }
+
+    // release empty slabs to the heap
+    pub fn shrink(&self) -> usize {
+        unsafe { self.cache.lock().shrink(&mut HeapPages) }
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        self.cache.lock().stats()
+    }
+}
+
+impl<T> fmt::Display for ObjectCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", *self.cache.lock())
+    }
+}
+
+
+// an object allocated from an ObjectCache, similar to Box
+pub struct CacheBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static ObjectCache<T>
+}
+
+impl<T> Deref for CacheBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.lock().deallocate(self.ptr.as_ptr() as *mut u8, &mut HeapPages);
+        }
+    }
+}
+
+
+/*
+The object sizes of SlabAllocator
+Unlike BLOCK_SIZES, the sizes are not restricted to powers of two.
+For allocations larger than 1024 bytes, we fall back to linkedlist allocator
+*/
+pub const SLAB_SIZES: &[usize] = &[8, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024];
+
+// the largest power of two dividing size, which is the alignment of objects in its cache
+const fn size_align(size: usize) -> usize {
+    size & size.wrapping_neg()
+}
+
+// find the smallest cache that satisfies size and alignment of layout
+fn cache_index(layout: &Layout) -> Option<usize> {
+    SLAB_SIZES.iter()
+        .position(|&s| s >= layout.size() && size_align(s) >= layout.align())
+}
+
+
+// the slab allocator
+pub struct SlabAllocator {
+    caches: [SlabCache; SLAB_SIZES.len()],
+    fallback_allocator: LinkedListAllocator
+}
+
+impl SlabAllocator {
+    // create a new empty SlabAllocator
+    pub const fn new() -> Self {
+        const fn cache(name: &'static str, size: usize) -> SlabCache {
+            SlabCache::new(name, size, size_align(size))
+        }
+
+        SlabAllocator {
+            caches: [
+                cache("slab-8", 8), cache("slab-16", 16), cache("slab-24", 24),
+                cache("slab-32", 32), cache("slab-48", 48), cache("slab-64", 64),
+                cache("slab-96", 96), cache("slab-128", 128), cache("slab-192", 192),
+                cache("slab-256", 256), cache("slab-384", 384), cache("slab-512", 512),
+                cache("slab-768", 768), cache("slab-1024", 1024)
+            ],
+            fallback_allocator: LinkedListAllocator::new()
+        }
+    }
+
+    // initialize the allocator with given heap bounds
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    // add a new memory region to the heap, e.g. after the heap has grown
+    // unsafe: the caller needs to ensure the memory region is valid and unused
+    pub unsafe fn extend(&mut self, addr: usize, size: usize) {
+        self.fallback_allocator.extend(addr, size);
+    }
+
+    // release empty slabs of all caches to the fallback allocator
+    pub fn shrink(&mut self) -> usize {
+        let SlabAllocator { caches, fallback_allocator } = self;
+        caches.iter_mut()
+            .map(|cache| unsafe { cache.shrink(fallback_allocator) })
+            .sum()
+    }
+
+    pub fn caches(&self) -> &[SlabCache] {
+        &self.caches
+    }
+
+    // the free regions of the fallback allocator
+    pub fn fallback_region_stats(&self) -> FreeRegionStats {
+        self.fallback_allocator.free_region_stats()
+    }
+}
+
+
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        // use destructuring to borrow the cache and the fallback allocator at the same time
+        let SlabAllocator { caches, fallback_allocator } = &mut *allocator;
+        match cache_index(&layout) {
+            // small objects are allocated from the slab caches
+            Some(index) => caches[index].allocate(fallback_allocator),
+            // the size is too large for any cache: use fallback allocator
+            None => fallback_allocator.allocate(layout)
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        let SlabAllocator { caches, fallback_allocator } = &mut *allocator;
+        match cache_index(&layout) {
+            Some(index) => caches[index].deallocate(ptr, fallback_allocator),
+            None => fallback_allocator.deallocate(ptr, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (cache_index(&layout), cache_index(&new_layout)) {
+            // the object is large enough for the new size
+            (Some(index), Some(new_index)) if index == new_index => return ptr,
+            // large allocations are resized in place by the fallback allocator
+            (None, None) => {
+                if self.lock().fallback_allocator.resize_in_place(ptr, layout, new_size) {
+                    return ptr;
+                }
+            },
+            _ => {}
+        }
+        realloc_by_copy(self, ptr, layout, new_size)
+    }
+}
@@ -0,0 +1,127 @@
+/*
+Heap allocation statistics
+
+The counters are updated by the global allocator (KernelAllocator) on every
+allocation and deallocation, so they work for every heap allocator.
+The state of free lists is read from the heap allocator when a snapshot is taken
+*/
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::fixed_size_block::BLOCK_SIZES;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static FAILED_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// record a successful allocation of size bytes
+pub(super) fn record_alloc(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+// record a deallocation of size bytes
+pub(super) fn record_dealloc(size: usize) {
+    ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+// record a reallocation from old_size to new_size bytes
+pub(super) fn record_realloc(old_size: usize, new_size: usize) {
+    if new_size >= old_size {
+        let allocated = ALLOCATED.fetch_add(new_size - old_size, Ordering::Relaxed) + new_size - old_size;
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+    } else {
+        ALLOCATED.fetch_sub(old_size - new_size, Ordering::Relaxed);
+    }
+}
+
+// record an allocation that returned null
+pub(super) fn record_failure() {
+    FAILED_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+
+// the free regions of a linkedlist allocator
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FreeRegionStats {
+    pub regions: usize,     // number of free regions
+    pub free_bytes: usize,  // total size of free regions
+    pub largest: usize      // size of the largest free region
+}
+
+impl FreeRegionStats {
+    /*
+    External fragmentation in percent: the part of free memory that cannot be used
+    by a single allocation. 0 means all free memory is in one region
+    */
+    pub fn fragmentation(&self) -> usize {
+        if self.free_bytes == 0 {
+            0
+        } else {
+            100 - self.largest * 100 / self.free_bytes
+        }
+    }
+}
+
+
+// a snapshot of heap statistics
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub heap_size: usize,   // size of mapped heap memory
+    pub allocated: usize,   // bytes currently allocated
+    pub peak: usize,        // the maximum of allocated bytes so far
+    pub allocations: usize,     // number of successful allocations
+    pub deallocations: usize,   // number of deallocations
+    pub failed_allocations: usize,  // number of allocations that returned null
+    // free list length of each block size in BLOCK_SIZES (fixed-size block allocator only)
+    pub free_blocks: Option<[usize; BLOCK_SIZES.len()]>,
+    // free regions of the (fallback) linkedlist allocator
+    pub free_regions: Option<FreeRegionStats>
+}
+
+impl HeapStats {
+    // take a snapshot of the global counters, free list state is filled by the caller
+    pub(super) fn new(heap_size: usize) -> Self {
+        HeapStats {
+            heap_size,
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+            peak: PEAK.load(Ordering::Relaxed),
+            allocations: ALLOCATIONS.load(Ordering::Relaxed),
+            deallocations: DEALLOCATIONS.load(Ordering::Relaxed),
+            failed_allocations: FAILED_ALLOCATIONS.load(Ordering::Relaxed),
+            free_blocks: None,
+            free_regions: None
+        }
+    }
+
+    // number of allocations that are not freed yet
+    pub fn live_allocations(&self) -> usize {
+        self.allocations - self.deallocations
+    }
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap size: {} bytes", self.heap_size)?;
+        writeln!(f, "allocated: {} bytes (peak {} bytes)", self.allocated, self.peak)?;
+        writeln!(f, "allocations: {}, deallocations: {}, live: {}, failed: {}",
+            self.allocations, self.deallocations, self.live_allocations(), self.failed_allocations)?;
+        if let Some(free_blocks) = self.free_blocks {
+            write!(f, "free blocks:")?;
+            for (size, count) in BLOCK_SIZES.iter().zip(free_blocks.iter()) {
+                write!(f, " {}B: {}", size, count)?;
+            }
+            writeln!(f)?;
+        }
+        if let Some(free_regions) = self.free_regions {
+            writeln!(f, "free regions: {} ({} bytes, largest {} bytes, fragmentation {}%)",
+                free_regions.regions, free_regions.free_bytes, free_regions.largest,
+                free_regions.fragmentation())?;
+        }
+        Ok(())
+    }
+}
@@ -1,39 +1,60 @@
+use core::cell::UnsafeCell;
+use core::ptr::addr_of;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
 
+use crate::memory::stack::KernelStack;
+
 // use stack 0 at IST to handle double fault
+// A stack overflow causes a page fault that cannot be delivered on the overflowed stack,
+// so it becomes a double fault, which reports the overflow on this stack. Page faults use
+// the current stack, so a page fault inside the page fault handler is safe
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+// the stacks used for each IST entry, and their names reported on stack overflow
+const IST_STACKS: [(u16, &str); 1] = [
+    (DOUBLE_FAULT_IST_INDEX, "double fault")
+];
+
+
+/*
+The stacks used by IST entries before memory management is initialized
+
+They are arrays in kernel data without guard pages, so init_stacks replaces them
+with kernel stacks that have guard pages once the memory manager is available
+*/
+static mut BOOT_STACKS: [[u8; IST_STACK_SIZE]; IST_STACKS.len()] = [[0; IST_STACK_SIZE]; IST_STACKS.len()];
 
 // singleton initialization of TSS
 /*
 TSS contains pointer to up to 7 interruption stacks,
 which can be used to handle stackoverflow
+
+The CPU reads the IST entries from TSS on every interrupt, so the TSS is in an UnsafeCell
+for init_stacks to replace the stacks after TSS is loaded
 */
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        // create the stack for double fault
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] ={
-            /*
-            NOTE:
-            This is only a temporary solution. We haven't implemented memory management
-            and we just allocate an array as the stack
-             */
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe{&STACK});
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss 
-    };
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+// the IST entries are only changed by init and init_stacks, see set_interrupt_stack
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    /*
+    Set the stack of IST entry index to the stack with top
+    unsafe: no interrupt may use the entry while it is replaced
+    */
+    unsafe fn set_interrupt_stack(&self, index: u16, top: VirtAddr) {
+        (*self.0.get()).interrupt_stack_table[index as usize] = top;
+    }
 }
 
+static TSS: Tss = Tss(UnsafeCell::new(TaskStateSegment::new()));
+
 
 // specific which GDT and TSS the CPU should use
 struct Selectors {
@@ -46,7 +67,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));  // select the custom TSS
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));  // select the custom TSS
         (gdt, Selectors {code_selector, tss_selector})
     };
 }
@@ -55,9 +76,36 @@ pub fn init() {
     use x86_64::instructions::tables::load_tss;
     use x86_64::instructions::segmentation::{CS, Segment};
 
+    // use the boot stacks until init_stacks is called
+    for (i, &(index, _)) in IST_STACKS.iter().enumerate() {
+        unsafe {
+            let stack_start = VirtAddr::from_ptr(addr_of!(BOOT_STACKS[i]));
+            TSS.set_interrupt_stack(index, stack_start + IST_STACK_SIZE);
+        }
+    }
+
     GDT.0.load();   // load GDT
     unsafe {
         CS::set_reg(GDT.1.code_selector);   // load kernal code segment
         load_tss(GDT.1.tss_selector);   // load our custom TSS
     }
-}
\ No newline at end of file
+}
+
+/*
+Allocate a kernel stack with guard page for each IST entry
+This requires memory::MEMORY_MANAGER, so it is called after memory initialization
+*/
+pub fn init_stacks() {
+    use x86_64::instructions::interrupts;
+
+    for &(index, name) in IST_STACKS.iter() {
+        let stack = KernelStack::new(IST_STACK_SIZE as u64, name)
+            .expect("failed to allocate interrupt stack");
+        // no interrupt can use the IST entry while it is replaced
+        interrupts::without_interrupts(|| unsafe {
+            TSS.set_interrupt_stack(index, stack.top());
+        });
+        // the interrupt stacks are used until the kernel stops
+        core::mem::forget(stack);
+    }
+}
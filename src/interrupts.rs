@@ -2,10 +2,15 @@ use x86_64::structures::idt::{
     InterruptDescriptorTable, 
     InterruptStackFrame, 
     PageFaultErrorCode};
-use crate::{println, eprintln, gdt, hlt_loop};
+use crate::{println, eprintln, gdt, hlt_loop, memory};
+use crate::time::TickSource;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// local APIC and I/O APIC
+pub mod apic;
 
 
 use crate::print;
@@ -24,7 +29,8 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard = PIC_1_OFFSET + 1
+    Keyboard = PIC_1_OFFSET + 1,
+    Rtc = PIC_2_OFFSET      // IRQ 8
 }
 
 impl InterruptIndex {
@@ -35,6 +41,12 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    // the IRQ line at the 8259 PICs
+    fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+
 }
 
 
@@ -46,6 +58,84 @@ pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(
 );
 
 
+// the interrupt controller handling hardware interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    Pic8259,
+    Apic
+}
+
+// whether the APIC replaced the 8259 PICs, EOIs are sent to the active controller
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/*
+Select the interrupt controller, the 8259 PICs (initialized by crate::init) are used
+until this is called
+
+The APIC needs memory::MEMORY_MANAGER to map its registers. If the APIC is preferred but
+not available, the 8259 PICs are kept. Return the controller in use
+*/
+pub fn init_controller(preferred: InterruptController) -> InterruptController {
+    use x86_64::instructions::interrupts;
+
+    if preferred == InterruptController::Apic && !APIC_ACTIVE.load(Ordering::SeqCst) {
+        // no interrupt can arrive while the controllers are switched
+        interrupts::without_interrupts(|| {
+            let timer = InterruptIndex::Timer.as_u8();
+            let keyboard = InterruptIndex::Keyboard.as_u8();
+            if apic::init(timer, keyboard) {
+                unsafe { PICS.lock().disable() };
+                APIC_ACTIVE.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+    active_controller()
+}
+
+pub fn active_controller() -> InterruptController {
+    if APIC_ACTIVE.load(Ordering::SeqCst) {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic8259
+    }
+}
+
+/*
+Enable the interrupt at the active interrupt controller
+The timer and keyboard interrupts are enabled by crate::init and init_controller
+*/
+pub fn enable_irq(index: InterruptIndex) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if APIC_ACTIVE.load(Ordering::SeqCst) {
+            apic::route_irq(index.irq(), index.as_u8());
+        } else {
+            let mut pics = PICS.lock();
+            let [mut mask_1, mut mask_2] = unsafe { pics.read_masks() };
+            match index.irq() {
+                irq @ 0..=7 => mask_1 &= !(1 << irq),
+                // the secondary PIC is connected to IRQ 2 of the primary PIC
+                irq => {
+                    mask_2 &= !(1 << (irq - 8));
+                    mask_1 &= !(1 << 2);
+                }
+            }
+            unsafe { pics.write_masks(mask_1, mask_2) };
+        }
+    });
+}
+
+// send end of interrupt (EOI) signal to the active interrupt controller
+fn end_of_interrupt(index: InterruptIndex) {
+    if APIC_ACTIVE.load(Ordering::SeqCst) {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+
 // singleton initialization of IDT
 /*
 Interruption Description Table (IDT) is used to store pointers
@@ -66,12 +156,20 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        // add handler of page fault
+        // add handler of page fault, it runs on the current stack (see gdt), so the
+        // page fault handler can itself cause page faults, e.g. in demand paged areas
         idt.page_fault.set_handler_fn(page_fault_handler);
         // add handler of timer interrupt
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         // add handler of keyboard interrupt
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        // add handler of the periodic RTC interrupt
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        // add handlers of spurious interrupts, the 8259 PICs send them as IRQ 7 and 15
+        // even when they are disabled
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[(PIC_1_OFFSET + 7) as usize].set_handler_fn(spurious_interrupt_handler);
+        idt[(PIC_2_OFFSET + 7) as usize].set_handler_fn(spurious_irq15_handler);
 
         idt
     };
@@ -97,6 +195,14 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
+    use x86_64::registers::control::Cr2;
+
+    // a stack overflow: the page fault in the guard page could not be delivered on the stack
+    let addr = Cr2::read();
+    if let Some(stack) = memory::stack::guard_page_owner(addr) {
+        panic!("EXCEPTION: DOUBLE FAULT\nstack overflow in stack {}\nAccessed Address: {:?}\n{:#?}",
+            stack, addr, stack_frame);
+    }
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -107,8 +213,28 @@ extern "x86-interrupt" fn page_fault_handler(
 {
     use x86_64::registers::control::Cr2;
 
+    // a write to a copy-on-write page, the page is copied (or made writable)
+    // and the write is retried after return
+    let addr = Cr2::read();
+    if memory::cow::handle_cow_fault(addr, error_code) {
+        return;
+    }
+
+    // a page of a demand paged area is accessed for the first time,
+    // the page is mapped now and the access is retried after return
+    if memory::vma::handle_page_fault(addr, error_code) {
+        return;
+    }
+
+    // the access hit the guard page below a kernel stack (without overflowing the current
+    // stack, an overflow is reported by the double fault handler)
+    if let Some(stack) = memory::stack::guard_page_owner(addr) {
+        panic!("EXCEPTION: PAGE FAULT\nstack overflow in stack {}\nAccessed Address: {:?}\n{:#?}",
+            stack, addr, stack_frame);
+    }
+
     eprintln!("EXCEPTION: PAGE FAULT");
-    eprintln!("Accessed Address: {:?}", Cr2::read());
+    eprintln!("Accessed Address: {:?}", addr);
     eprintln!("Error Code: {:?}", error_code);
     eprintln!("{:#?}", stack_frame);
     hlt_loop();
@@ -121,16 +247,17 @@ extern "x86-interrupt" fn page_fault_handler(
 extern "x86-interrupt" fn timer_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    // print!(".");
+    // count the tick for timekeeping, and wake the async tasks whose sleep has ended
+    if crate::time::tick(TickSource::Pit) {
+        crate::task::timer::wake_expired();
+    }
 
     // send end of interrupt (EOI) signal to interrupt handler
     /*
     The interrupt controller needs an explicit EOI signal from interrupt handler
     Otherwise, it is waiting for the current interrupt to be handled
      */
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
@@ -157,8 +284,36 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(
     // add scancode to scancode queue
     crate::task::keyboard::add_scancode(scancode);
 
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+// the handler for the periodic RTC interrupt, an alternative tick source
+extern "x86-interrupt" fn rtc_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    // the RTC sends no more interrupts until this one is acknowledged
+    crate::time::rtc::acknowledge_interrupt();
+    if crate::time::tick(TickSource::Rtc) {
+        crate::task::timer::wake_expired();
+    }
+
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+// spurious interrupts are not real interrupts and get no EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+}
+
+// a spurious IRQ 15 of the secondary PIC is a real IRQ 2 (the cascade) at the primary PIC,
+// so only the primary PIC gets an EOI
+extern "x86-interrupt" fn spurious_irq15_handler(
+    _stack_frame: InterruptStackFrame)
+{
     unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        // the EOI of an IRQ of the primary PIC is only sent to the primary PIC
+        PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 2);
     }
 }
 
@@ -0,0 +1,350 @@
+/*
+Local APIC and I/O APIC
+
+The APIC replaces the 8259 PICs as interrupt controller:
+- the local APIC of the CPU receives the interrupts and takes the end of interrupt (EOI)
+  signal. Its registers are at the physical address in the IA32_APIC_BASE MSR
+- the I/O APIC receives the interrupts of devices and sends them to a local APIC.
+  Each input (global system interrupt, GSI) has an entry in its redirection table,
+  which selects the vector and destination of the interrupt
+
+Both are accessed through memory mapped registers (memory::map_mmio).
+The address of the I/O APIC and the GSIs of the legacy IRQs are read from the ACPI MADT
+(the table "APIC"): a legacy IRQ is connected to the GSI with the same number, unless an
+interrupt source override of the MADT connects it to another GSI (e.g. the timer IRQ 0
+to GSI 2), possibly level triggered or active low. Without MADT, the standard address and
+the routing of PC compatible firmware are used
+*/
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::__cpuid;
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+use crate::acpi;
+use crate::memory::{self, MmioRegion};
+
+// the vector of spurious interrupts of the local APIC, its lowest 4 bits must be set
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// the routing used without MADT: the standard I/O APIC address, and the timer IRQ 0
+// connected to GSI 2 (the other legacy IRQs are connected to the GSI with their number)
+const DEFAULT_IO_APIC_BASE: u64 = 0xFEC0_0000;
+const DEFAULT_TIMER_GSI: u32 = 2;
+
+const LEGACY_IRQS: usize = 16;
+
+// the MADT: the entries start after the header, the local APIC address and the flags,
+// each entry starts with its type and length
+const MADT_ENTRIES: usize = 44;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+// the polarity (bits 0-1) and trigger mode (bits 2-3) of an override, 0 is the ISA default
+const OVERRIDE_ACTIVE_LOW: u16 = 0b11;
+const OVERRIDE_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+// local APIC registers (offsets)
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SPURIOUS: usize = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+
+// I/O APIC registers: the register selected by IOREGSEL is accessed through IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+static IO_APIC: spin::Mutex<Option<IoApic>> = spin::Mutex::new(None);
+static ROUTING: OnceCell<Routing> = OnceCell::uninit();
+
+
+// the I/O APIC input of a legacy IRQ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool
+}
+
+impl IrqRoute {
+    // the ISA default: the GSI with the number of the IRQ, edge triggered and active high
+    const fn identity(irq: u8) -> Self {
+        IrqRoute { gsi: irq as u32, active_low: false, level_triggered: false }
+    }
+
+    // the polarity and trigger mode bits of the redirection entry
+    fn entry_flags(&self) -> u64 {
+        let active_low = if self.active_low { REDIRECTION_ACTIVE_LOW } else { 0 };
+        let level_triggered = if self.level_triggered { REDIRECTION_LEVEL_TRIGGERED } else { 0 };
+        active_low | level_triggered
+    }
+}
+
+// the address of the I/O APIC and the inputs of the legacy IRQs
+#[derive(Debug, Clone, Copy)]
+struct Routing {
+    io_apic_base: PhysAddr,
+    irqs: [IrqRoute; LEGACY_IRQS]
+}
+
+// every legacy IRQ connected to the GSI with its number
+fn identity_routes() -> [IrqRoute; LEGACY_IRQS] {
+    let mut irqs = [IrqRoute::identity(0); LEGACY_IRQS];
+    for (irq, route) in irqs.iter_mut().enumerate() {
+        *route = IrqRoute::identity(irq as u8);
+    }
+    irqs
+}
+
+impl Routing {
+    // the routing of PC compatible firmware, used without MADT
+    fn default() -> Self {
+        let mut irqs = identity_routes();
+        irqs[0].gsi = DEFAULT_TIMER_GSI;
+        Routing { io_apic_base: PhysAddr::new(DEFAULT_IO_APIC_BASE), irqs }
+    }
+
+    /*
+    Read the routing from the MADT
+    Return None if there is no MADT, or no I/O APIC for the GSIs from 0 (the legacy IRQs)
+    */
+    fn from_madt() -> Option<Self> {
+        let table = acpi::find_table(b"APIC")?;
+        let length = unsafe { acpi::read_phys::<acpi::SdtHeader>(table) }.length as usize;
+        let mut io_apic_base = None;
+        let mut irqs = identity_routes();
+
+        let mut offset = MADT_ENTRIES;
+        while offset + 2 <= length {
+            let entry = table + offset;
+            let (entry_type, entry_length) = unsafe {
+                (acpi::read_phys::<u8>(entry), acpi::read_phys::<u8>(entry + 1u64) as usize)
+            };
+            if entry_length < 2 {
+                break;
+            }
+            match entry_type {
+                // id (2), address (4), GSI base (8)
+                MADT_IO_APIC if entry_length >= 12 => {
+                    let (address, gsi_base) = unsafe {
+                        (acpi::read_phys::<u32>(entry + 4u64), acpi::read_phys::<u32>(entry + 8u64))
+                    };
+                    if gsi_base == 0 {
+                        io_apic_base = Some(PhysAddr::new(address as u64));
+                    }
+                },
+                // bus (2), IRQ (3), GSI (4), flags (8)
+                MADT_INTERRUPT_SOURCE_OVERRIDE if entry_length >= 10 => {
+                    let (irq, gsi, flags) = unsafe {
+                        (acpi::read_phys::<u8>(entry + 3u64) as usize,
+                            acpi::read_phys::<u32>(entry + 4u64),
+                            acpi::read_phys::<u16>(entry + 8u64))
+                    };
+                    if irq < LEGACY_IRQS {
+                        irqs[irq] = IrqRoute {
+                            gsi,
+                            active_low: flags & OVERRIDE_ACTIVE_LOW == OVERRIDE_ACTIVE_LOW,
+                            level_triggered: flags & OVERRIDE_LEVEL_TRIGGERED == OVERRIDE_LEVEL_TRIGGERED
+                        };
+                    }
+                },
+                _ => {}
+            }
+            offset += entry_length;
+        }
+        Some(Routing { io_apic_base: io_apic_base?, irqs })
+    }
+}
+
+
+pub struct LocalApic {
+    regs: MmioRegion
+}
+
+impl LocalApic {
+    // map the local APIC at the address in IA32_APIC_BASE and enable it
+    fn new() -> Option<Self> {
+        let mut base_msr = Msr::new(IA32_APIC_BASE);
+        let base = unsafe { base_msr.read() };
+        let regs = memory::map_mmio(PhysAddr::new(base & APIC_BASE_ADDRESS_MASK), 0x400)?;
+        unsafe { base_msr.write(base | APIC_BASE_ENABLE) };
+
+        let apic = LocalApic { regs };
+        // accept all interrupts, and set the spurious vector with the software enable bit
+        apic.regs.write::<u32>(LAPIC_TASK_PRIORITY, 0);
+        apic.regs.write::<u32>(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32);
+        Some(apic)
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.regs.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.regs.write::<u32>(LAPIC_EOI, 0);
+    }
+}
+
+
+pub struct IoApic {
+    regs: MmioRegion
+}
+
+impl IoApic {
+    fn new(base: PhysAddr) -> Option<Self> {
+        Some(IoApic { regs: memory::map_mmio(base, 0x20)? })
+    }
+
+    fn read(&mut self, register: u32) -> u32 {
+        self.regs.write::<u32>(IOREGSEL, register);
+        self.regs.read::<u32>(IOWIN)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.regs.write::<u32>(IOREGSEL, register);
+        self.regs.write::<u32>(IOWIN, value);
+    }
+
+    // the number of entries in the redirection table
+    pub fn entries(&mut self) -> u32 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1
+    }
+
+    // the redirection entry of gsi
+    pub fn entry(&mut self, gsi: u32) -> u64 {
+        let low = self.read(IOAPIC_REDIRECTION_TABLE + 2 * gsi) as u64;
+        let high = self.read(IOAPIC_REDIRECTION_TABLE + 2 * gsi + 1) as u64;
+        high << 32 | low
+    }
+
+    fn set_entry(&mut self, gsi: u32, entry: u64) {
+        // mask the entry while it is changed, the low half with the mask bit is written last
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * gsi, REDIRECTION_MASKED as u32);
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * gsi + 1, (entry >> 32) as u32);
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * gsi, entry as u32);
+    }
+
+    /*
+    Send the interrupts of gsi with vector to the local APIC with apic_id
+    The interrupt is edge triggered and active high (like ISA IRQs), with fixed delivery
+    */
+    pub fn route(&mut self, gsi: u32, vector: u8, apic_id: u8) {
+        self.set_entry(gsi, (apic_id as u64) << 56 | vector as u64);
+    }
+
+    // send the interrupts of a legacy IRQ with vector to the local APIC with apic_id
+    pub fn route_irq(&mut self, route: IrqRoute, vector: u8, apic_id: u8) {
+        self.set_entry(route.gsi, (apic_id as u64) << 56 | route.entry_flags() | vector as u64);
+    }
+
+    pub fn mask(&mut self, gsi: u32) {
+        let entry = self.entry(gsi);
+        self.set_entry(gsi, entry | REDIRECTION_MASKED);
+    }
+}
+
+
+// check whether the CPU has a local APIC (CPUID.01h:EDX bit 9)
+pub fn is_supported() -> bool {
+    // __cpuid is only unsafe in older Rust versions
+    #[allow(unused_unsafe)]
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+// the routing read by init, the default routing before
+fn routing() -> Routing {
+    ROUTING.try_get().ok().copied().unwrap_or_else(Routing::default)
+}
+
+// the I/O APIC input of the legacy IRQ irq (0 to 15)
+pub fn irq_route(irq: u8) -> IrqRoute {
+    routing().irqs[irq as usize]
+}
+
+/*
+Map and enable the local APIC and the I/O APIC, and route the timer and keyboard IRQs to
+vectors timer_vector and keyboard_vector. All other I/O APIC inputs are masked
+
+Return false if there is no APIC or its registers cannot be mapped.
+The 8259 PICs need to be disabled by the caller, see interrupts::init_controller
+*/
+pub fn init(timer_vector: u8, keyboard_vector: u8) -> bool {
+    if !is_supported() || LOCAL_APIC.is_initialized() {
+        return false;
+    }
+    let _ = ROUTING.try_init_once(|| Routing::from_madt().unwrap_or_else(Routing::default));
+    // map and check the I/O APIC first, so the local APIC is not left enabled without it.
+    // If the local APIC cannot be mapped, dropping io_apic unmaps its registers
+    let mut io_apic = match IoApic::new(routing().io_apic_base) {
+        Some(io_apic) => io_apic,
+        None => return false
+    };
+    for gsi in 0..io_apic.entries() {
+        io_apic.mask(gsi);
+    }
+    let local_apic = match LocalApic::new() {
+        Some(local_apic) => local_apic,
+        None => return false
+    };
+
+    let apic_id = local_apic.id();
+    io_apic.route_irq(irq_route(0), timer_vector, apic_id);
+    io_apic.route_irq(irq_route(1), keyboard_vector, apic_id);
+
+    *IO_APIC.lock() = Some(io_apic);
+    LOCAL_APIC.try_init_once(|| local_apic).is_ok()
+}
+
+// check whether init enabled the APIC
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_initialized()
+}
+
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.try_get().ok()
+}
+
+// send the interrupts of gsi with vector to this CPU, return false if the APIC is not initialized
+pub fn route(gsi: u32, vector: u8) -> bool {
+    match (local_apic(), IO_APIC.lock().as_mut()) {
+        (Some(local_apic), Some(io_apic)) => {
+            io_apic.route(gsi, vector, local_apic.id());
+            true
+        },
+        _ => false
+    }
+}
+
+// send the interrupts of the legacy IRQ irq with vector to this CPU, see route
+pub fn route_irq(irq: u8, vector: u8) -> bool {
+    match (local_apic(), IO_APIC.lock().as_mut()) {
+        (Some(local_apic), Some(io_apic)) => {
+            io_apic.route_irq(irq_route(irq), vector, local_apic.id());
+            true
+        },
+        _ => false
+    }
+}
+
+// the redirection entry of gsi, None if the I/O APIC is not initialized
+pub fn redirection_entry(gsi: u32) -> Option<u64> {
+    IO_APIC.lock().as_mut().map(|io_apic| io_apic.entry(gsi))
+}
+
+// signal the end of the current interrupt to the local APIC
+pub fn end_of_interrupt() {
+    if let Some(local_apic) = local_apic() {
+        local_apic.end_of_interrupt();
+    }
+}
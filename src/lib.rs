@@ -21,6 +21,8 @@ pub mod gdt;
 pub mod memory;
 pub mod allocator;
 pub mod task;
+pub mod time;
+pub mod acpi;
 
 
 /*
@@ -65,6 +67,54 @@ pub fn test_panic_handler(info: &PanicInfo) -> ! {
     loop {}
 }
 
+// collects a panic message, so that a test can check which error was reported
+struct MessageBuffer {
+    buffer: [u8; 256],
+    len: usize
+}
+
+impl core::fmt::Write for MessageBuffer {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let count = s.len().min(self.buffer.len() - self.len);
+        self.buffer[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+/*
+expected_panic_handler is called when a panic happens in a test that expects
+a panic whose message contains expected (e.g. an error reported by the heap allocator)
+*/
+pub fn expected_panic_handler(info: &PanicInfo, expected: &str) -> ! {
+    use core::fmt::Write;
+
+    let mut message = MessageBuffer { buffer: [0; 256], len: 0 };
+    let _ = write!(message, "{}", info);
+    let message = core::str::from_utf8(&message.buffer[..message.len]).unwrap_or("");
+    if message.contains(expected) {
+        serial_println!("[OK]");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+    test_panic_handler(info)
+}
+
+// initialize the kernel, the memory manager and the kernel heap for a test
+pub fn init_test_heap(boot_info: &'static bootloader::BootInfo) {
+    use memory::BitmapFrameAllocator;
+    use x86_64::VirtAddr;
+
+    init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+}
+
 
 // exit the kernel once the test completes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -93,6 +143,7 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 pub fn init() {
     gdt::init();    // initialize gdt
     interrupts::init_idt();  // initialize interruptions
+    time::init();   // set the frequency of timer interrupts
     unsafe {interrupts::PICS.lock().initialize()}   // initialize PIC
     x86_64::instructions::interrupts::enable();     // enable interrupt controller for CPU 
 }
@@ -11,6 +11,10 @@ use rust_core::{eprintln, println, task::{simple_executor, keyboard}};
 use bootloader::{BootInfo, entry_point};
 use x86_64::VirtAddr;
 use rust_core::task::{Task, executor::Executor};
+use rust_core::interrupts::{self, InterruptController};
+
+// the interrupt controller used after boot, the 8259 PICs are kept if there is no APIC
+const INTERRUPT_CONTROLLER: InterruptController = InterruptController::Apic;
 
 /*
 panic handler for non-test configuration (cargo run)
@@ -59,15 +63,26 @@ fn kernal_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     use rust_core::allocator;
-    use rust_core::memory::{self, BootInfoFrameAllocator};
+    use rust_core::memory::{self, BitmapFrameAllocator};
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::print_memory_map(&boot_info.memory_map);
+    frame_allocator.print_zones();
+
+    memory::init_memory_manager(mapper, frame_allocator);
+    memory::remap_kernel_text();
+    memory::enforce_wx();
+    assert_eq!(memory::page_table::audit_wx(), 0, "writable and executable pages remain");
+    rust_core::gdt::init_stacks();
+    allocator::init_heap().expect("heap initialization failed");
+    let controller = interrupts::init_controller(INTERRUPT_CONTROLLER);
+    println!("interrupt controller: {:?}", controller);
+    let clocksource = rust_core::time::clocksource::init();
+    println!("clock source: {} ({} Hz)", clocksource.name(), clocksource.frequency());
 
     let mut executor = Executor::new();
     executor.spawn(Task::new(example_task()));
@@ -1,19 +1,51 @@
 use x86_64::{
     structures::paging::{
-        PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator
+        PageTable, OffsetPageTable, PhysFrame, Size4KiB, FrameAllocator,
+        FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size1GiB, Size2MiB
     },
+    structures::paging::mapper::{MapToError, MappedFrame, Translate, TranslateResult},
     structures::paging::page_table::FrameError,
     VirtAddr,
     PhysAddr,
-    registers::control::Cr3
+    registers::control::{Cr0, Cr0Flags, Cr3},
+    registers::model_specific::{Efer, EferFlags}
 };
 
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
+use crate::serial_println;
+
+// physical frame allocators
+pub mod frame_allocator;
+
+// kernel virtual address space manager
+pub mod vma;
+// kernel stacks with guard pages
+pub mod stack;
+// page table walker, dump and W^X audit
+pub mod page_table;
+// memory mapped device registers
+pub mod mmio;
+// copy-on-write page sharing
+pub mod cow;
+// per-process address spaces
+pub mod address_space;
+
+pub use frame_allocator::{BitmapFrameAllocator, MemoryZone};
+pub use mmio::{map_mmio, MmioRegion, MmioValue};
+pub use address_space::AddressSpace;
+
 /*
 Initialize a new OffsetPageTable
+
+This also enables the NO_EXECUTE flag (EFER.NXE), which is a reserved bit in page table
+entries otherwise, and write protection (CR0.WP), so read-only pages cannot be written
+by the kernel either
 */
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+
     // retrieve a reference to level 4 page table
     let level_4_table = active_level_4_table(physical_memory_offset);
     // create offset page table
@@ -21,12 +53,346 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 }
 
 
+/*
+The page table mapper and frame allocator of the kernel
+
+They are stored in MEMORY_MANAGER after initialization, so that subsystems
+(e.g. the heap allocator) can map new pages at runtime.
+
+Note: the heap allocator locks MEMORY_MANAGER when the heap grows, so code holding
+the lock must never allocate heap memory, otherwise it deadlocks
+*/
+pub struct MemoryManager {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator
+}
+
+impl MemoryManager {
+    /*
+    Create the level 3 page tables of the kernel virtual memory (memory::vma)
+
+    Address spaces copy the level 4 entries of the kernel page table, so the level 4
+    entries of the kernel virtual memory must exist before any address space is created,
+    otherwise later kernel mappings are not visible in the address space
+    */
+    fn populate_kernel_tables(&mut self) {
+        let physical_memory_offset = self.mapper.phys_offset();
+        let first = (vma::KERNEL_AREA_START >> 39) as usize;
+        let last = ((vma::KERNEL_AREA_END - 1) >> 39) as usize;
+        for index in first..=last {
+            if !self.mapper.level_4_table()[index].is_unused() {
+                continue;
+            }
+            let frame: PhysFrame<Size4KiB> = self.frame_allocator
+                .allocate_frame()
+                .expect("no frame for kernel page table");
+            let table: *mut PageTable = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+            unsafe { (*table).zero() };
+            self.mapper.level_4_table()[index]
+                .set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        }
+    }
+
+    /*
+    Map the virtual memory from start to start + size to newly allocated frames
+
+    Parts of the range that are 2 MiB aligned are mapped with 2 MiB pages if the frame
+    allocator can supply 2 MiB aligned contiguous frames, which saves page tables and
+    TLB entries. The rest of the range, or all of it when no such frames are left,
+    is mapped with 4 KiB pages.
+    On error, the pages mapped before the error stay mapped
+    */
+    pub fn map_range(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        let end = start + size;
+        let mut addr = start.align_down(Size4KiB::SIZE);
+        while addr < end {
+            if addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE
+                && self.map_huge_page(addr, flags) {
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+
+            let page: Page<Size4KiB> = Page::containing_address(addr);
+            let frame: PhysFrame<Size4KiB> = self.frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                    return Err(err);
+                }
+            }
+            addr += Size4KiB::SIZE;
+        }
+
+        Ok(())
+    }
+
+    // map a newly allocated frame filled with zeros at the page containing addr
+    pub fn map_zeroed_page(&mut self, addr: VirtAddr, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        let page: Page<Size4KiB> = Page::containing_address(addr);
+        let frame: PhysFrame<Size4KiB> = self.frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        // clear the frame through the physical memory mapping, the page may not be writable
+        let frame_ptr: *mut u8 = (self.mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize) };
+
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(())
+            },
+            Err(err) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                Err(err)
+            }
+        }
+    }
+
+    /*
+    Unmap the pages from start to start + size and free their frames
+
+    Pages that are not mapped are skipped. A 2 MiB page must be completely inside the range.
+    Note: the page tables are not freed even if they become empty
+    */
+    pub fn unmap_range(&mut self, start: VirtAddr, size: u64) {
+        let end = start + size;
+        let mut addr = start.align_down(Size4KiB::SIZE);
+        while addr < end {
+            match self.mapper.translate(addr) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => {
+                    let page: Page<Size4KiB> = Page::containing_address(addr);
+                    let (frame, flush) = self.mapper.unmap(page).expect("failed to unmap page");
+                    flush.flush();
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                    addr += Size4KiB::SIZE;
+                },
+                TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => {
+                    assert!(addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE,
+                        "cannot unmap part of a 2 MiB page at {:#x}", addr.as_u64());
+                    let page: Page<Size2MiB> = Page::containing_address(addr);
+                    let (frame, flush) = self.mapper.unmap(page).expect("failed to unmap page");
+                    flush.flush();
+                    unsafe { self.frame_allocator.deallocate_frame(frame) };
+                    addr += Size2MiB::SIZE;
+                },
+                TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } =>
+                    panic!("cannot unmap 1 GiB page at {:#x}", addr.as_u64()),
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) =>
+                    addr += Size4KiB::SIZE
+            }
+        }
+    }
+
+    /*
+    Map the pages from start to start + size to the physical memory from phys (e.g. device registers)
+
+    The frames are not taken from the frame allocator, only the page tables are.
+    On error, the pages mapped before the error stay mapped
+    */
+    pub fn map_device_range(&mut self, start: VirtAddr, phys: PhysAddr, size: u64, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        let pages = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        for i in 0..pages {
+            let page: Page<Size4KiB> = Page::containing_address(start + i * Size4KiB::SIZE);
+            let frame: PhysFrame<Size4KiB> = PhysFrame::containing_address(phys + i * Size4KiB::SIZE);
+            unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator)?.flush() };
+        }
+        Ok(())
+    }
+
+    // unmap the pages mapped by map_device_range, the frames are not freed
+    pub fn unmap_device_range(&mut self, start: VirtAddr, size: u64) {
+        let pages = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+        for i in 0..pages {
+            let page: Page<Size4KiB> = Page::containing_address(start + i * Size4KiB::SIZE);
+            if let Ok((_, flush)) = self.mapper.unmap(page) {
+                flush.flush();
+            }
+        }
+    }
+
+    // try to map a 2 MiB page at addr (2 MiB aligned)
+    // return false if there is no 2 MiB frame available or the page cannot be mapped
+    // (e.g. part of it is already mapped with 4 KiB pages)
+    fn map_huge_page(&mut self, addr: VirtAddr, flags: PageTableFlags) -> bool {
+        let page: Page<Size2MiB> = Page::containing_address(addr);
+        let frame: PhysFrame<Size2MiB> = match self.frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return false
+        };
+        // the HUGE_PAGE flag is added by the mapper
+        match unsafe { self.mapper.map_to(page, frame, flags, &mut self.frame_allocator) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    }
+}
+
+pub static MEMORY_MANAGER: spin::Mutex<Option<MemoryManager>> = spin::Mutex::new(None);
+
+// store the mapper and frame allocator of the kernel in MEMORY_MANAGER
+pub fn init_memory_manager(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    assert!(memory_manager.is_none(), "memory manager already initialized");
+    let mut manager = MemoryManager { mapper, frame_allocator };
+    manager.populate_kernel_tables();
+    *memory_manager = Some(manager);
+}
+
+
+// the ELF header of the kernel, defined by the linker (lld) at the start of the first segment
+extern "C" {
+    static __ehdr_start: u8;
+}
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/*
+The virtual address and size of the executable segments of the kernel
+
+They are read from the program headers of the kernel ELF file. The ELF header and the
+program headers are loaded with the first segment, at __ehdr_start
+*/
+fn kernel_text_segments() -> impl Iterator<Item = (VirtAddr, u64)> {
+    // taking the address of an extern static is only unsafe in older Rust versions
+    #[allow(unused_unsafe)]
+    let ehdr = unsafe { core::ptr::addr_of!(__ehdr_start) } as usize;
+    // read 8 bytes at addr, the fields of the ELF structures are not necessarily aligned
+    let read = |addr: usize| unsafe { (addr as *const u64).read_unaligned() };
+    assert_eq!(read(ehdr) as u32, u32::from_le_bytes(*ELF_MAGIC), "kernel ELF header not found");
+    // e_phoff, e_phentsize and e_phnum
+    let phoff = read(ehdr + 32) as usize;
+    let phentsize = read(ehdr + 54) as u16 as usize;
+    let phnum = (read(ehdr + 56) as u16) as usize;
+
+    (0..phnum).filter_map(move |i| {
+        // p_type and p_flags, p_vaddr and p_memsz
+        let header = ehdr + phoff + i * phentsize;
+        let (p_type, p_flags) = (read(header) as u32, (read(header) >> 32) as u32);
+        let (vaddr, memsz) = (read(header + 16), read(header + 40));
+        if p_type == PT_LOAD && p_flags & PF_X != 0 && memsz > 0 {
+            Some((VirtAddr::new(vaddr), memsz))
+        } else {
+            None
+        }
+    })
+}
+
+/*
+Remap the kernel code read-only
+
+The executable segments are found in the program headers of the kernel (see
+kernel_text_segments), and every page in them is made read-only
+*/
+pub fn remap_kernel_text() {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let mapper = &mut memory_manager.as_mut().expect("memory manager not initialized").mapper;
+
+    for (start, size) in kernel_text_segments() {
+        let end = start + size;
+        let mut addr = start.align_down(Size4KiB::SIZE);
+        while addr < end {
+            match mapper.translate(addr) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), flags, .. } => {
+                    let page: Page<Size4KiB> = Page::containing_address(addr);
+                    unsafe {
+                        mapper.update_flags(page, flags & !PageTableFlags::WRITABLE)
+                            .expect("failed to update flags of kernel code")
+                            .flush();
+                    }
+                    addr += Size4KiB::SIZE;
+                },
+                TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), flags, .. } => {
+                    // the huge page must not contain data of other segments
+                    assert!(addr.is_aligned(Size2MiB::SIZE) && end - addr >= Size2MiB::SIZE,
+                        "kernel code shares a 2 MiB page at {:#x}", addr.as_u64());
+                    let page: Page<Size2MiB> = Page::containing_address(addr);
+                    unsafe {
+                        mapper.update_flags(page, flags & !PageTableFlags::WRITABLE)
+                            .expect("failed to update flags of kernel code")
+                            .flush();
+                    }
+                    addr += Size2MiB::SIZE;
+                },
+                TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } =>
+                    panic!("kernel code in 1 GiB page at {:#x}", addr.as_u64()),
+                // e.g. the zero-initialized end of a segment that is not mapped
+                TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) =>
+                    addr += Size4KiB::SIZE
+            }
+        }
+    }
+}
+
+/*
+Make every writable and executable page non-executable, return the number of changed pages
+
+This is called after remap_kernel_text, when no kernel code is writable anymore.
+The remaining W^X violations are data mapped by the bootloader: the kernel data segments,
+the boot stack, the boot info, the VGA buffer and the physical memory mapping
+*/
+pub fn enforce_wx() -> usize {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let mapper = &mut memory_manager.as_mut().expect("memory manager not initialized").mapper;
+    let physical_memory_offset = mapper.phys_offset();
+
+    // the walk borrows the page table, so it restarts after each change
+    let mut count = 0;
+    loop {
+        let violation = unsafe { page_table::Mappings::new(mapper.level_4_table(), physical_memory_offset) }
+            .find(|mapping| mapping.is_writable_and_executable());
+        let mapping = match violation {
+            Some(mapping) => mapping,
+            None => return count
+        };
+
+        let set_no_execute = |flags: PageTableFlags| flags | PageTableFlags::NO_EXECUTE;
+        unsafe {
+            match mapper.translate(mapping.start) {
+                TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), flags, .. } =>
+                    mapper.update_flags(Page::<Size4KiB>::containing_address(mapping.start), set_no_execute(flags))
+                        .map(|flush| flush.flush()),
+                TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), flags, .. } =>
+                    mapper.update_flags(Page::<Size2MiB>::containing_address(mapping.start), set_no_execute(flags))
+                        .map(|flush| flush.flush()),
+                TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), flags, .. } =>
+                    mapper.update_flags(Page::<Size1GiB>::containing_address(mapping.start), set_no_execute(flags))
+                        .map(|flush| flush.flush()),
+                _ => unreachable!("mapping not found in page table")
+            }
+        }.expect("failed to update flags of W^X violation");
+        count += 1;
+    }
+}
+
+
 // return a mutable reference to the level 4 page table
 /*
 We retrieve the physical address of level 4 page table from Cr3 register through bootloader
 Then, the physical address is mapped to the corresponding virtual address
+
+unsafe: the complete physical memory must be mapped at physical_memory_offset, and the
+returned reference aliases the table used by the mapper in MEMORY_MANAGER.
+To inspect the active page table, page_table::for_each_run is safer
 */
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
+pub unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable
 {
     let (level_4_table_frame, _) = Cr3::read();  // read the address from cr3 register
@@ -39,7 +405,40 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
 }
 
 
+/*
+Print the memory map passed from bootloader to serial
+
+Every region is printed with its physical range, type and size, followed by
+the total size of each region type
+*/
+pub fn print_memory_map(memory_map: &MemoryMap) {
+    // the total size of each region type, there are only a few different types
+    let mut totals: [Option<(MemoryRegionType, u64)>; 16] = [None; 16];
+
+    serial_println!("physical memory map:");
+    for region in memory_map.iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        serial_println!("{:#012x}-{:#012x} {:?} ({} KiB)",
+            region.range.start_addr(), region.range.end_addr(), region.region_type, size / 1024);
+
+        let total = totals.iter_mut()
+            .find(|total| matches!(total, Some((region_type, _)) if *region_type == region.region_type)
+                || total.is_none());
+        match total {
+            Some(Some((_, bytes))) => *bytes += size,
+            Some(total) => *total = Some((region.region_type, size)),
+            None => {}  // more types than expected, they are not counted
+        }
+    }
+
+    for (region_type, bytes) in totals.iter().flatten() {
+        serial_println!("total {:?}: {} KiB", region_type, bytes / 1024);
+    }
+}
+
+
 // create a FrameAllocator from memory map passed from bootloader
+// Note: frames allocated by this allocator can never be freed, use BitmapFrameAllocator instead
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize
@@ -94,6 +493,10 @@ x86_64 had provided abstractions for converting virtual address to physical addr
 OffsetPageTable type is used when we map the complete physical memory to virtual memory
 (which is our case)
 OffsetPageTable provides mapping for both normal pages and huge pages
+
+Huge pages end the walk early: a level 3 entry with HUGE_PAGE flag maps a 1 GiB page,
+and a level 2 entry with HUGE_PAGE flag maps a 2 MiB page. The rest of the virtual
+address is the offset into the huge page
 */
 pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr)
     -> Option<PhysAddr>
@@ -126,7 +529,8 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
     let mut frame = level_4_table_frame;
 
     // iterate through 4 levels of page tables to find the physical address
-    for &index in &table_indexes {
+    for (i, &index) in table_indexes.iter().enumerate() {
+        let level = 4 - i;
         // access the virtual address of the next-level page table
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
@@ -137,7 +541,14 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr)
         frame = match entry.frame() {
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported")
+            Err(FrameError::HugeFrame) => {
+                let page_size = match level {
+                    3 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    _ => panic!("huge page flag set in level {} page table", level)
+                };
+                return Some(entry.addr() + (addr.as_u64() & (page_size - 1)));
+            }
         };
     }
 
@@ -0,0 +1,197 @@
+/*
+Per-process address spaces
+
+An AddressSpace has its own level 4 page table. All mappings of the kernel are shared:
+the level 4 entries of the kernel page table are copied, so the new table points to the
+same level 3 tables. Only the user region (USER_START to USER_END) is private, user pages
+are mapped there with USER_ACCESSIBLE.
+
+Since only level 4 entries are copied, kernel mappings added later are only visible in
+existing address spaces if their level 4 entry already existed. The level 3 tables of the
+kernel virtual memory (memory::vma) are created at initialization for this reason
+(see MemoryManager::populate_kernel_tables).
+
+An address space is activated by loading its level 4 table into Cr3.
+When it is dropped, all frames and page tables of the user region are freed
+*/
+use x86_64::{
+    registers::control::{Cr3, Cr3Flags},
+    structures::paging::{
+        mapper::{MapToError, Translate},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB
+    },
+    PhysAddr,
+    VirtAddr
+};
+
+use super::{BitmapFrameAllocator, MEMORY_MANAGER};
+
+// the virtual memory of user programs (level 4 entries 32 to 63)
+pub const USER_START: u64 = 0x_1000_0000_0000;
+pub const USER_END: u64 = 0x_2000_0000_0000;
+
+const USER_L4_INDEXES: core::ops::Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+// the flags of page tables in the user region, the flags of each page restrict them further
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::USER_ACCESSIBLE);
+
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr
+}
+
+impl AddressSpace {
+    /*
+    Create an address space sharing the kernel mappings with an empty user region
+    Return None if there is no frame for the level 4 table
+    */
+    pub fn new() -> Option<Self> {
+        let mut memory_manager = MEMORY_MANAGER.lock();
+        let memory_manager = memory_manager.as_mut().expect("memory manager not initialized");
+        let physical_memory_offset = memory_manager.mapper.phys_offset();
+        let level_4_frame: PhysFrame = memory_manager.frame_allocator.allocate_frame()?;
+
+        let kernel_table = memory_manager.mapper.level_4_table();
+        let table = unsafe { &mut *table_ptr(physical_memory_offset, level_4_frame.start_address()) };
+        table.zero();
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if USER_L4_INDEXES.contains(&index) {
+                assert!(entry.is_unused(), "kernel mapping in user region at level 4 entry {}", index);
+            } else {
+                table[index] = entry.clone();
+            }
+        }
+
+        Some(AddressSpace { level_4_frame, physical_memory_offset })
+    }
+
+    // the physical frame of the level 4 table, which is loaded into Cr3 on activation
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /*
+    Map the user pages from start to start + size to newly allocated frames with flags
+    (USER_ACCESSIBLE is added). The range must be in the user region
+    */
+    pub fn map_user(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags)
+        -> Result<(), MapToError<Size4KiB>>
+    {
+        assert!(USER_START <= start.as_u64() && start.as_u64() + size <= USER_END,
+            "{:#x}-{:#x} is not in the user region", start.as_u64(), start.as_u64() + size);
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let mut memory_manager = MEMORY_MANAGER.lock();
+        let frame_allocator = &mut memory_manager.as_mut().expect("memory manager not initialized").frame_allocator;
+        let physical_memory_offset = self.physical_memory_offset;
+        let mut mapper = unsafe { self.mapper() };
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(start + size - 1u64)
+        );
+        for page in pages {
+            let frame = frame_allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+            // clear the frame, so no data of the kernel or other programs is visible
+            unsafe {
+                let frame_ptr: *mut u8 = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+                core::ptr::write_bytes(frame_ptr, 0, Size4KiB::SIZE as usize);
+            }
+            match unsafe { mapper.map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, frame_allocator) } {
+                // the address space may not be active, flush anyway in case it is
+                Ok(flush) => flush.flush(),
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // translate a virtual address in this address space
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { self.mapper() }.translate_addr(addr)
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    /*
+    Switch to this address space by loading its level 4 table into Cr3
+    unsafe: the address space must not be dropped while it is active,
+    switch to another address space (e.g. activate_kernel) first
+    */
+    pub unsafe fn activate(&self) {
+        Cr3::write(self.level_4_frame, Cr3Flags::empty());
+    }
+
+    // a mapper of the level 4 table of this address space
+    // unsafe: the mapper must not be used at the same time as another mapper of the table
+    unsafe fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = &mut *table_ptr(self.physical_memory_offset, self.level_4_frame.start_address());
+        OffsetPageTable::new(table, self.physical_memory_offset)
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropping the active address space");
+
+        let mut memory_manager = MEMORY_MANAGER.lock();
+        let frame_allocator = &mut memory_manager.as_mut().expect("memory manager not initialized").frame_allocator;
+        let table = unsafe { &mut *table_ptr(self.physical_memory_offset, self.level_4_frame.start_address()) };
+        // the other entries belong to the kernel and are shared
+        for index in USER_L4_INDEXES {
+            if !table[index].is_unused() {
+                unsafe { free_table(table[index].addr(), 3, self.physical_memory_offset, frame_allocator) };
+            }
+        }
+        unsafe { frame_allocator.deallocate_frame(self.level_4_frame) };
+    }
+}
+
+
+// switch back to the kernel page table (the page table set up by the bootloader)
+pub fn activate_kernel() {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let mapper = &mut memory_manager.as_mut().expect("memory manager not initialized").mapper;
+    let virt = VirtAddr::from_ptr(mapper.level_4_table() as *const PageTable);
+    let phys = PhysAddr::new(virt - mapper.phys_offset());
+    unsafe { Cr3::write(PhysFrame::containing_address(phys), Cr3Flags::empty()) };
+}
+
+fn table_ptr(physical_memory_offset: VirtAddr, table: PhysAddr) -> *mut PageTable {
+    (physical_memory_offset + table.as_u64()).as_mut_ptr()
+}
+
+/*
+Free the page table at table (of level), the tables below it, and the frames they map
+unsafe: the page table must not be used anymore
+*/
+unsafe fn free_table(
+    table: PhysAddr,
+    level: u32,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut BitmapFrameAllocator
+) {
+    let page_table = &mut *table_ptr(physical_memory_offset, table);
+    for entry in page_table.iter() {
+        if entry.is_unused() {
+            continue;
+        }
+        if level == 1 {
+            frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(entry.addr()));
+        } else if level == 2 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            frame_allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr()));
+        } else {
+            free_table(entry.addr(), level - 1, physical_memory_offset, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(PhysFrame::<Size4KiB>::containing_address(table));
+}
@@ -0,0 +1,142 @@
+/*
+Copy-on-write page sharing
+
+share_region maps the frames of one virtual range at another range, without copying.
+Writable pages become read-only in both ranges and are marked with COW_FLAG (an available
+bit of the page table entry), and the frame allocator counts the references to each frame.
+
+A write to a shared page causes a protection violation page fault, handle_cow_fault then:
+- copies the frame to a new private frame and maps it writable, if the frame is still shared
+- makes the page writable in place, if this is the last reference to the frame
+*/
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size4KiB, Translate
+    },
+    VirtAddr
+};
+
+use super::{vma, MEMORY_MANAGER};
+
+// marks a read-only page that is writable after copying
+pub const COW_FLAG: PageTableFlags = PageTableFlags::BIT_9;
+
+// the flags of page tables created for shared pages, so the pages can become writable later
+const TABLE_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE);
+
+
+/*
+Share the pages from src to src + size at dst to dst + size copy-on-write
+
+Both ranges must be in reserved areas. Pages of src that are not mapped are skipped,
+and the pages of dst must not be mapped. Only 4 KiB pages can be shared
+*/
+pub fn share_region(src: VirtAddr, dst: VirtAddr, size: u64) -> Result<(), MapToError<Size4KiB>> {
+    for start in [src, dst] {
+        let reserved = vma::find_area(start).map_or(false, |area| area.contains_range(start, size));
+        assert!(reserved, "{:#x}-{:#x} is not in a reserved virtual memory area",
+            start.as_u64(), start.as_u64() + size);
+    }
+
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let memory_manager = memory_manager.as_mut().expect("memory manager not initialized");
+    let pages = (size + Size4KiB::SIZE - 1) / Size4KiB::SIZE;
+    for i in 0..pages {
+        let src_page: Page<Size4KiB> = Page::containing_address(src + i * Size4KiB::SIZE);
+        let dst_page: Page<Size4KiB> = Page::containing_address(dst + i * Size4KiB::SIZE);
+        let (frame, flags) = match memory_manager.mapper.translate(src_page.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => (frame, flags),
+            TranslateResult::Mapped { .. } =>
+                panic!("copy-on-write of huge page at {:#x}", src_page.start_address().as_u64()),
+            _ => continue
+        };
+
+        // read-only pages can be shared as they are
+        let shared_flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags & !PageTableFlags::WRITABLE) | COW_FLAG
+        } else {
+            flags
+        };
+        unsafe {
+            if shared_flags != flags {
+                memory_manager.mapper.update_flags(src_page, shared_flags)
+                    .expect("failed to update flags of shared page")
+                    .flush();
+            }
+            memory_manager.mapper
+                .map_to_with_table_flags(dst_page, frame, shared_flags, TABLE_FLAGS, &mut memory_manager.frame_allocator)?
+                .flush();
+        }
+        memory_manager.frame_allocator.add_reference(frame);
+    }
+
+    Ok(())
+}
+
+
+/*
+Handle a page fault at addr, return true if it was a write to a copy-on-write page
+that is now writable, so the write can be retried
+
+The fault happened in the active page table, which is accessed through a new mapper.
+MEMORY_MANAGER is locked meanwhile, so its mapper is not used at the same time
+*/
+pub fn handle_cow_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE) {
+        return false;
+    }
+
+    let mut memory_manager = match MEMORY_MANAGER.try_lock() {
+        Some(memory_manager) => memory_manager,
+        None => return false
+    };
+    let memory_manager = match memory_manager.as_mut() {
+        Some(memory_manager) => memory_manager,
+        None => return false
+    };
+    let physical_memory_offset = memory_manager.mapper.phys_offset();
+    let mut mapper = unsafe {
+        OffsetPageTable::new(super::active_level_4_table(physical_memory_offset), physical_memory_offset)
+    };
+
+    let page: Page<Size4KiB> = Page::containing_address(addr);
+    let (frame, flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. }
+            if flags.contains(COW_FLAG) => (frame, flags),
+        _ => return false
+    };
+    let private_flags = (flags & !COW_FLAG) | PageTableFlags::WRITABLE;
+
+    // the last reference to the frame, no copy is needed
+    if memory_manager.frame_allocator.reference_count(frame) == 1 {
+        return match unsafe { mapper.update_flags(page, private_flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            },
+            Err(_) => false
+        };
+    }
+
+    let copy: PhysFrame<Size4KiB> = match memory_manager.frame_allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false
+    };
+    unsafe {
+        let src: *const u8 = (physical_memory_offset + frame.start_address().as_u64()).as_ptr();
+        let dst: *mut u8 = (physical_memory_offset + copy.start_address().as_u64()).as_mut_ptr();
+        core::ptr::copy_nonoverlapping(src, dst, Size4KiB::SIZE as usize);
+
+        // replace the shared frame with the copy, the TLB entry is flushed after mapping
+        mapper.unmap(page).expect("failed to unmap shared page").1.ignore();
+        mapper.map_to_with_table_flags(page, copy, private_flags, TABLE_FLAGS, &mut memory_manager.frame_allocator)
+            .expect("failed to map copied page")
+            .flush();
+        // drop the reference of this page to the shared frame
+        memory_manager.frame_allocator.deallocate_frame(frame);
+    }
+    true
+}
@@ -0,0 +1,416 @@
+/*
+Implementation of a bitmap frame allocator
+
+The allocator keeps one bit for every physical frame up to the end of the last
+usable memory region: a set bit means the frame is used (or not usable at all),
+a cleared bit means the frame is free.
+
+The bitmap itself is stored in the first usable region that is large enough to
+hold it, and is accessed through the complete physical memory mapping set up by
+the bootloader. Therefore the allocator does not depend on the heap and can be
+used to create the heap mapping.
+
+Compared with BootInfoFrameAllocator:
+- frames can be returned with FrameDeallocator and are reused later
+- allocation scans the bitmap a whole word (64 frames) at a time, starting from
+  the position of the last allocation (next-fit), instead of walking the memory map
+- physically contiguous frames can be allocated together, which is also used
+  to allocate 2 MiB frames for huge pages
+- frames can be allocated from a memory zone, for devices that can only access
+  low physical memory (see MemoryZone)
+- a frame can be shared by several mappings (copy-on-write): the allocator counts the
+  extra references of each frame, and deallocate_frame only frees a frame when its
+  last reference is dropped. The counters are stored after the bitmap
+*/
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB
+    },
+    structures::paging::frame::PhysFrameRange,
+    PhysAddr,
+    VirtAddr
+};
+use core::slice;
+
+use crate::serial_println;
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = 64;
+
+
+/*
+Physical memory zones
+
+A zone is the physical memory below its limit:
+- Dma: below 16 MiB, reachable by ISA DMA controllers
+- Dma32: below 4 GiB, reachable by devices with 32-bit addresses
+- Normal: all physical memory
+
+Allocations without zone prefer the memory above 4 GiB, then above 16 MiB,
+so that low memory is left for the devices that need it. Allocations in a zone
+likewise search the lower zones last
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryZone {
+    Dma,
+    Dma32,
+    Normal
+}
+
+impl MemoryZone {
+    // the end (exclusive) of the physical memory in zone
+    pub const fn limit(self) -> u64 {
+        match self {
+            MemoryZone::Dma => 16 * 1024 * 1024,
+            MemoryZone::Dma32 => 4 * 1024 * 1024 * 1024,
+            MemoryZone::Normal => u64::MAX
+        }
+    }
+
+    // the frame index at the limit
+    fn frame_limit(self) -> usize {
+        frame_index_at(self.limit())
+    }
+}
+
+// the physical memory ranges searched in order by allocations without zone
+const PREFERRED_RANGES: [(u64, u64); 3] = [
+    (MemoryZone::Dma32.limit(), MemoryZone::Normal.limit()),
+    (MemoryZone::Dma.limit(), MemoryZone::Dma32.limit()),
+    (0, MemoryZone::Dma.limit())
+];
+
+// the preferred ranges in zone, e.g. Dma32 searches 16 MiB to 4 GiB before the Dma zone
+fn zone_ranges(zone: MemoryZone) -> impl Iterator<Item = &'static (u64, u64)> {
+    PREFERRED_RANGES.iter().filter(move |&&(_, end)| end <= zone.limit())
+}
+
+fn frame_index_at(addr: u64) -> usize {
+    (addr / FRAME_SIZE).min(usize::MAX as u64) as usize
+}
+
+
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],     // one bit per frame, 1 = used
+    shares: &'static mut [u16],     // the number of extra references to each used frame
+    total_frames: usize,    // number of usable frames managed by the allocator
+    free_frames: usize,     // number of frames currently free
+    next: usize     // the frame index to start searching from
+}
+
+impl BitmapFrameAllocator {
+    // create a BitmapFrameAllocator from memory map passed from bootloader
+    // unsafe: the caller needs to ensure the memory map is valid and the complete
+    // physical memory is mapped at physical_memory_offset.
+    // This method should only be called once
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        // the bitmap covers every frame from address 0 to the end of the last usable region
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .expect("no usable memory region");
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let word_count = (frame_count + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        // the reference counters follow the bitmap, one u16 for each bit
+        let shares_size = (word_count * BITS_PER_WORD * 2) as u64;
+        let bitmap_size = (word_count * 8) as u64 + shares_size;
+
+        // place the bitmap at the start of the first usable region that can hold it
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable memory region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        let shares_ptr = bitmap_ptr.add(word_count) as *mut u16;
+        let shares = slice::from_raw_parts_mut(shares_ptr, word_count * BITS_PER_WORD);
+        shares.fill(0);
+
+        // initially every frame is used, then we release the frames in usable regions
+        // the bits after frame_count are never cleared, so they can never be allocated
+        bitmap.fill(u64::MAX);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            total_frames: 0,
+            free_frames: 0,
+            next: 0
+        };
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            for index in start..end {
+                allocator.clear_bit(index);
+            }
+            allocator.total_frames += end - start;
+        }
+
+        // the frames storing the bitmap and reference counters are not available for allocation
+        let bitmap_frames = ((bitmap_size + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for index in first_bitmap_frame..first_bitmap_frame + bitmap_frames {
+            allocator.set_bit(index);
+        }
+        allocator.total_frames -= bitmap_frames;
+        allocator.free_frames = allocator.total_frames;
+
+        allocator
+    }
+
+    // the number of usable frames managed by the allocator
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    // the number of frames that are currently free
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    // the number of frames that are currently allocated
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    // check whether the given frame is currently free
+    pub fn is_free(&self, frame: PhysFrame) -> bool {
+        let index = Self::frame_index(frame);
+        self.manages(index) && !self.is_used(index)
+    }
+
+    /*
+    Add a reference to a used frame, which is shared by one more mapping
+    The frame is only freed after deallocate_frame is called once for every reference
+    */
+    pub fn add_reference(&mut self, frame: PhysFrame) {
+        let index = Self::frame_index(frame);
+        assert!(self.manages(index), "reference to frame {:?} not managed by the frame allocator", frame);
+        assert!(self.is_used(index), "reference to free frame {:?}", frame);
+        self.shares[index] = self.shares[index].checked_add(1).expect("too many references to frame");
+    }
+
+    // the number of references to frame, 0 if the frame is free
+    pub fn reference_count(&self, frame: PhysFrame) -> usize {
+        let index = Self::frame_index(frame);
+        if self.manages(index) && self.is_used(index) {
+            self.shares[index] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    // the number of free frames in zone
+    pub fn zone_free_frames(&self, zone: MemoryZone) -> usize {
+        let end = zone.frame_limit().min(self.bitmap.len() * BITS_PER_WORD);
+        (0..end).filter(|&index| !self.is_used(index)).count()
+    }
+
+    // allocate a frame in zone
+    pub fn allocate_frame_in(&mut self, zone: MemoryZone) -> Option<PhysFrame> {
+        let index = zone_ranges(zone).find_map(|&(start, end)| {
+            self.find_free_frame(frame_index_at(start), frame_index_at(end))
+        })?;
+        self.set_bit(index);
+        self.free_frames -= 1;
+        Some(Self::frame_at(index))
+    }
+
+    /*
+    Allocate count physically contiguous frames
+    The first frame is aligned to align frames (align must be a power of two),
+    e.g. align = 512 returns a range starting at a 2 MiB boundary
+
+    The search is a linear scan of the bitmap, so this is slower than allocate_frame
+    */
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        PREFERRED_RANGES.iter().find_map(|&(start, end)| {
+            self.allocate_contiguous_between(count, align, frame_index_at(start), frame_index_at(end))
+        })
+    }
+
+    // allocate count physically contiguous frames in zone, see allocate_contiguous
+    pub fn allocate_contiguous_in(&mut self, count: usize, align: usize, zone: MemoryZone)
+        -> Option<PhysFrameRange>
+    {
+        zone_ranges(zone).find_map(|&(start, end)| {
+            self.allocate_contiguous_between(count, align, frame_index_at(start), frame_index_at(end))
+        })
+    }
+
+    // allocate count contiguous frames with frame indexes in [first, end)
+    fn allocate_contiguous_between(&mut self, count: usize, align: usize, first: usize, end: usize)
+        -> Option<PhysFrameRange>
+    {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let end = end.min(self.bitmap.len() * BITS_PER_WORD);
+        let mut start = align_up(first, align);
+        while start + count <= end {
+            // find the first used frame in [start, start + count)
+            match (start..start + count).find(|&index| self.is_used(index)) {
+                // restart after the used frame
+                Some(used) => start = align_up(used + 1, align),
+                None => {
+                    for index in start..start + count {
+                        self.set_bit(index);
+                    }
+                    self.free_frames -= count;
+                    return Some(PhysFrame::range(
+                        Self::frame_at(start),
+                        Self::frame_at(start + count)
+                    ));
+                }
+            }
+        }
+
+        None
+    }
+
+    // release a range of frames returned by allocate_contiguous
+    // unsafe: the caller needs to ensure the frames are no longer used
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+
+    // print the free memory of each zone to serial
+    pub fn print_zones(&self) {
+        serial_println!("free frames: {} of {} ({} KiB)",
+            self.free_frames, self.total_frames, self.free_frames as u64 * FRAME_SIZE / 1024);
+        for zone in [MemoryZone::Dma, MemoryZone::Dma32, MemoryZone::Normal] {
+            let free = self.zone_free_frames(zone);
+            serial_println!("zone {:?}: {} free frames ({} KiB)", zone, free, free as u64 * FRAME_SIZE / 1024);
+        }
+    }
+
+    /*
+    Find a free frame with index in [first, end)
+
+    The bitmap is scanned a whole word (64 frames) at a time, starting from the word
+    of the last allocation (next-fit) and wrapping around once
+    */
+    fn find_free_frame(&self, first: usize, end: usize) -> Option<usize> {
+        let end = end.min(self.bitmap.len() * BITS_PER_WORD);
+        if first >= end {
+            return None;
+        }
+        let first_word = first / BITS_PER_WORD;
+        let end_word = (end + BITS_PER_WORD - 1) / BITS_PER_WORD;
+        let word_count = end_word - first_word;
+        let next_word = self.next.clamp(first, end - 1) / BITS_PER_WORD;
+
+        for offset in 0..word_count {
+            let word_index = first_word + (next_word - first_word + offset) % word_count;
+            let mut word = self.bitmap[word_index];
+            // the frames outside [first, end) are treated as used
+            if word_index == first_word {
+                word |= (1 << (first % BITS_PER_WORD)) - 1;
+            }
+            if word_index == end_word - 1 && end % BITS_PER_WORD != 0 {
+                word |= !((1 << (end % BITS_PER_WORD)) - 1);
+            }
+            // skip the word if all 64 frames are used
+            if word != u64::MAX {
+                return Some(word_index * BITS_PER_WORD + (!word).trailing_zeros() as usize);
+            }
+        }
+
+        None
+    }
+
+    // convert between frames and their index in bitmap
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn frame_at(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    // check whether the frame at index is in the bitmap, frames after the end of usable
+    // memory (e.g. MMIO) are not
+    fn manages(&self, index: usize) -> bool {
+        index < self.bitmap.len() * BITS_PER_WORD
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if self.free_frames == 0 {
+            return None;
+        }
+
+        // search the preferred ranges in order, leave low memory for devices
+        let index = PREFERRED_RANGES.iter().find_map(|&(start, end)| {
+            self.find_free_frame(frame_index_at(start), frame_index_at(end))
+        })?;
+        self.set_bit(index);
+        self.free_frames -= 1;
+        self.next = index;
+        Some(Self::frame_at(index))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    // frames not managed by the allocator (e.g. MMIO frames of unmapped pages) are ignored
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = Self::frame_index(frame);
+        if !self.manages(index) {
+            return;
+        }
+        assert!(self.is_used(index), "frame {:?} is already free", frame);
+        // the frame is still used by other mappings
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            return;
+        }
+        self.clear_bit(index);
+        self.free_frames += 1;
+    }
+}
+
+
+// the number of 4 KiB frames in a 2 MiB frame
+const FRAMES_PER_2MIB: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+// a 2 MiB frame consists of 512 contiguous 4 KiB frames aligned to 2 MiB
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let range = self.allocate_contiguous(FRAMES_PER_2MIB, FRAMES_PER_2MIB)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(PhysFrame::range(start, start + FRAMES_PER_2MIB as u64));
+    }
+}
+
+
+// align index upwards to a multiple of align (power of two)
+fn align_up(index: usize, align: usize) -> usize {
+    (index + align - 1) & !(align - 1)
+}
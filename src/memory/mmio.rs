@@ -0,0 +1,119 @@
+/*
+Memory mapped I/O
+
+Device registers (e.g. APIC, HPET, PCI BARs) are accessed through physical addresses that
+are not normal memory. map_mmio reserves an area of kind Mmio in kernel virtual memory
+and maps the registers there with NO_CACHE and WRITE_THROUGH, so every access reaches the
+device and is not cached or combined by the CPU.
+
+The returned MmioRegion only allows volatile accesses inside the region, so the compiler
+cannot remove or reorder them. The accesses are 1, 2, 4 or 8 bytes wide (the types that
+implement MmioValue) and aligned to their size, so each is a single access of the device
+register. The region is unmapped when MmioRegion is dropped
+*/
+use core::mem;
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    PhysAddr,
+    VirtAddr
+};
+
+use super::MEMORY_MANAGER;
+use super::vma::{self, AreaKind, VirtualArea};
+
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+// the types of register accesses: u8, u16, u32 and u64 (the trait is sealed)
+pub trait MmioValue: sealed::Sealed + Copy {}
+
+macro_rules! mmio_value {
+    ($($t:ty),*) => {
+        $(
+            impl sealed::Sealed for $t {}
+            impl MmioValue for $t {}
+        )*
+    };
+}
+
+mmio_value!(u8, u16, u32, u64);
+
+
+pub struct MmioRegion {
+    area: VirtualArea,
+    phys: PhysAddr,
+    virt: VirtAddr,     // the virtual address of phys
+    len: usize
+}
+
+/*
+Map len bytes of device memory starting at physical address phys
+
+phys does not need to be page aligned, the pages containing the region are mapped.
+Return None if there is no virtual memory or page table frame available.
+Note: the bootloader may also map the same physical memory (cached) at the physical
+memory offset, that mapping should not be used to access the device
+*/
+pub fn map_mmio(phys: PhysAddr, len: usize) -> Option<MmioRegion> {
+    let phys_start = phys.align_down(Size4KiB::SIZE);
+    let offset = phys - phys_start;
+    let size = offset + len as u64;
+    let area = vma::reserve(size, AreaKind::Mmio, "mmio")?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    let region = MmioRegion { area, phys, virt: area.start + offset, len };
+    let result = MEMORY_MANAGER.lock()
+        .as_mut()
+        .expect("memory manager not initialized")
+        .map_device_range(area.start, phys_start, size, flags);
+    // on error, the region is dropped and the mapped pages are unmapped
+    result.ok()?;
+    Some(region)
+}
+
+impl MmioRegion {
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn size(&self) -> usize {
+        self.len
+    }
+
+    // volatile read of a value of type T at offset bytes from the start of the region
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    // volatile write of a value of type T at offset bytes from the start of the region
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    // the pointer to a T at offset, panic if it is outside the region or not aligned to its size
+    fn ptr<T: MmioValue>(&self, offset: usize) -> *mut T {
+        assert!(offset.checked_add(mem::size_of::<T>()).map_or(false, |end| end <= self.len),
+            "MMIO access at offset {:#x} outside region of {:#x} bytes", offset, self.len);
+        assert_eq!(offset % mem::size_of::<T>(), 0, "misaligned MMIO access at offset {:#x}", offset);
+        (self.virt + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        // the frames belong to the device, unmap them before the area is released,
+        // since releasing an area frees the frames of mapped pages
+        MEMORY_MANAGER.lock()
+            .as_mut()
+            .expect("memory manager not initialized")
+            .unmap_device_range(self.area.start, self.area.size);
+        vma::release(self.area.start);
+    }
+}
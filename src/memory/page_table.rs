@@ -0,0 +1,342 @@
+/*
+Page table walker, dump and W^X audit
+
+Mappings iterates every present mapping in a level 4 page table, in order of virtual address:
+4 KiB pages in level 1 tables, and huge pages in level 2 (2 MiB) and level 3 (1 GiB) tables.
+The page tables are accessed through the complete physical memory mapping.
+MappingRuns merges the mappings into runs of pages that are contiguous in both virtual and
+physical memory and have the same page size and flags, which is much shorter to print.
+
+The flags of a mapping are the effective flags: a page is only writable (or user
+accessible) if all page table entries on the way to it are writable, and it is not
+executable if any entry on the way has NO_EXECUTE
+*/
+use core::fmt;
+use x86_64::{
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr,
+    VirtAddr
+};
+
+use crate::serial_println;
+use super::MEMORY_MANAGER;
+
+
+// a page mapped in page table
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    pub start: VirtAddr,
+    pub frame: PhysAddr,
+    pub size: u64,      // page size
+    pub flags: PageTableFlags
+}
+
+impl Mapping {
+    // W^X violation: the page can be written and executed
+    pub fn is_writable_and_executable(&self) -> bool {
+        is_writable_and_executable(self.flags)
+    }
+}
+
+fn is_writable_and_executable(flags: PageTableFlags) -> bool {
+    flags.contains(PageTableFlags::WRITABLE) && !flags.contains(PageTableFlags::NO_EXECUTE)
+}
+
+
+/*
+Iterator over the mappings of a page table
+
+The walk keeps the current table and entry index of each level, like a depth first
+search with an explicit stack, so it does not need heap memory
+*/
+pub struct Mappings<'a> {
+    physical_memory_offset: VirtAddr,
+    level: usize,   // the level of the table being scanned (1 to 4)
+    // indexed by level - 1: the table, the next entry index, the virtual address
+    // of the table and the effective flags of the entries on the way to the table
+    tables: [Option<&'a PageTable>; 4],
+    indexes: [usize; 4],
+    bases: [u64; 4],
+    flags: [PageTableFlags; 4]
+}
+
+impl<'a> Mappings<'a> {
+    // unsafe: the page tables must be mapped at physical_memory_offset
+    pub unsafe fn new(level_4_table: &'a PageTable, physical_memory_offset: VirtAddr) -> Self {
+        let root_flags = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        Mappings {
+            physical_memory_offset,
+            level: 4,
+            tables: [None, None, None, Some(level_4_table)],
+            indexes: [0; 4],
+            bases: [0; 4],
+            flags: [root_flags; 4]
+        }
+    }
+
+    // merge the mappings into contiguous runs
+    pub fn runs(self) -> MappingRuns<'a> {
+        MappingRuns { mappings: self, pending: None }
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        loop {
+            let i = self.level - 1;
+            // all entries of the table are visited, return to the parent table
+            if self.indexes[i] == 512 {
+                if self.level == 4 {
+                    return None;
+                }
+                self.level += 1;
+                continue;
+            }
+
+            let index = self.indexes[i];
+            self.indexes[i] += 1;
+            let entry = &self.tables[i].unwrap()[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                continue;
+            }
+
+            // the size of memory mapped by one entry at this level
+            let entry_size = 1u64 << (12 + 9 * i);
+            let start = self.bases[i] + index as u64 * entry_size;
+            let flags = effective_flags(self.flags[i], entry.flags());
+
+            // HUGE_PAGE is only valid in level 3 and level 2 entries
+            if self.level == 1 || (entry.flags().contains(PageTableFlags::HUGE_PAGE) && self.level <= 3) {
+                return Some(Mapping {
+                    // sign extend the address of the higher half
+                    start: VirtAddr::new_truncate(start),
+                    frame: entry.addr(),
+                    size: entry_size,
+                    flags
+                });
+            }
+
+            // descend into the next level table
+            let virt = self.physical_memory_offset + entry.addr().as_u64();
+            self.level -= 1;
+            self.tables[i - 1] = Some(unsafe { &*virt.as_ptr::<PageTable>() });
+            self.indexes[i - 1] = 0;
+            self.bases[i - 1] = start;
+            self.flags[i - 1] = flags;
+        }
+    }
+}
+
+// combine the flags of a page table entry with the flags of its parent entries
+fn effective_flags(parent_flags: PageTableFlags, flags: PageTableFlags) -> PageTableFlags {
+    let mut effective = flags;
+    for flag in [PageTableFlags::WRITABLE, PageTableFlags::USER_ACCESSIBLE] {
+        if !parent_flags.contains(flag) {
+            effective.remove(flag);
+        }
+    }
+    if parent_flags.contains(PageTableFlags::NO_EXECUTE) {
+        effective.insert(PageTableFlags::NO_EXECUTE);
+    }
+    effective
+}
+
+
+// the flags set by the CPU on access, they are ignored when merging runs
+const ACCESS_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+// pages contiguous in virtual and physical memory with the same page size and flags
+#[derive(Debug, Clone, Copy)]
+pub struct MappingRun {
+    pub start: VirtAddr,
+    pub frame: PhysAddr,    // the physical address of start
+    pub size: u64,          // the size of the run
+    pub page_size: u64,
+    pub flags: PageTableFlags
+}
+
+impl MappingRun {
+    // the end of the run, it wraps around (to 0) for a run at the end of the address space
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.start.as_u64().wrapping_add(self.size))
+    }
+
+    pub fn pages(&self) -> u64 {
+        self.size / self.page_size
+    }
+
+    /*
+    check whether mapping directly continues the run
+    The ends are computed without VirtAddr/PhysAddr arithmetic, which panics on overflow or
+    non-canonical addresses: a run ending at the end of the lower half or of the address
+    space is not continued
+    */
+    fn continues(&self, mapping: &Mapping) -> bool {
+        let end = self.start.as_u64().checked_add(self.size);
+        let frame_end = self.frame.as_u64().checked_add(self.size);
+        end == Some(mapping.start.as_u64())
+            && frame_end == Some(mapping.frame.as_u64())
+            && mapping.size == self.page_size
+            && mapping.flags & !ACCESS_FLAGS == self.flags
+    }
+}
+
+impl From<Mapping> for MappingRun {
+    fn from(mapping: Mapping) -> Self {
+        MappingRun {
+            start: mapping.start,
+            frame: mapping.frame,
+            size: mapping.size,
+            page_size: mapping.size,
+            flags: mapping.flags & !ACCESS_FLAGS
+        }
+    }
+}
+
+/*
+Print a run in a line, e.g.
+0x0000444444440000-0x0000444444459000 -> 0x0000000000437000   25 x 4K rw-k
+The flags are r (always, present), w (writable), x (executable), u (user) or k (kernel),
+and g (global), nc (no cache), wt (write through) if set
+*/
+impl fmt::Display for MappingRun {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let page_size = match self.page_size {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G"
+        };
+        write!(f, "{:#018x}-{:#018x} -> {:#018x} {:>5} x {} r{}{}{}",
+            self.start.as_u64(), self.end().as_u64(), self.frame.as_u64(),
+            self.pages(), page_size,
+            if self.flags.contains(PageTableFlags::WRITABLE) { "w" } else { "-" },
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { "-" } else { "x" },
+            if self.flags.contains(PageTableFlags::USER_ACCESSIBLE) { "u" } else { "k" })?;
+        let optional_flags = [
+            (PageTableFlags::GLOBAL, " g"),
+            (PageTableFlags::NO_CACHE, " nc"),
+            (PageTableFlags::WRITE_THROUGH, " wt")
+        ];
+        for (flag, name) in optional_flags {
+            if self.flags.contains(flag) {
+                write!(f, "{}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// iterator over the runs of mappings, see Mappings::runs
+pub struct MappingRuns<'a> {
+    mappings: Mappings<'a>,
+    pending: Option<Mapping>    // the mapping after the last returned run
+}
+
+impl<'a> Iterator for MappingRuns<'a> {
+    type Item = MappingRun;
+
+    fn next(&mut self) -> Option<MappingRun> {
+        let mut run = MappingRun::from(self.pending.take().or_else(|| self.mappings.next())?);
+        for mapping in &mut self.mappings {
+            if run.continues(&mapping) {
+                run.size += mapping.size;
+            } else {
+                self.pending = Some(mapping);
+                break;
+            }
+        }
+        Some(run)
+    }
+}
+
+
+// call f with the runs of the active page table (in MEMORY_MANAGER)
+// Note: MEMORY_MANAGER is locked while f runs, so f must not allocate heap memory
+pub fn for_each_run(mut f: impl FnMut(MappingRun)) {
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let mapper = &mut memory_manager.as_mut().expect("memory manager not initialized").mapper;
+    let physical_memory_offset = mapper.phys_offset();
+    let runs = unsafe { Mappings::new(mapper.level_4_table(), physical_memory_offset) }.runs();
+    for run in runs {
+        f(run);
+    }
+}
+
+/*
+The virtually contiguous mapped memory around addr, as (start, end)
+Return None if addr is not mapped. This locks MEMORY_MANAGER, see for_each_run
+*/
+pub fn mapped_range(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+    let mut range: Option<(VirtAddr, VirtAddr)> = None;
+    let mut complete = false;
+    for_each_run(|run| {
+        if complete {
+            return;
+        }
+        match range {
+            // the runs are sorted, extend the range while the next run directly follows it
+            Some((start, end)) if run.start == end => range = Some((start, run.end())),
+            Some(_) => complete = true,
+            None if run.start <= addr && addr < run.end() => range = Some((run.start, run.end())),
+            None => {}
+        }
+    });
+    range
+}
+
+// print every mapping of the active page table to serial
+pub fn dump() {
+    serial_println!("page table dump:");
+    for_each_run(|run| {
+        serial_println!("{}", run);
+    });
+}
+
+/*
+Audit the active page table for W^X violations
+
+Print every run of pages that is both writable and executable to serial,
+and return the number of such pages
+*/
+pub fn audit_wx() -> usize {
+    let mut count = 0;
+    for_each_run(|run| {
+        if is_writable_and_executable(run.flags) {
+            serial_println!("W^X violation: {:#x}-{:#x} is writable and executable",
+                run.start.as_u64(), run.end().as_u64());
+            count += run.pages() as usize;
+        }
+    });
+
+    serial_println!("W^X audit: {} writable and executable pages", count);
+    count
+}
+
+
+// test cases
+#[test_case]
+fn test_runs_at_address_space_ends() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mapping = |start: u64, frame: u64| Mapping {
+        start: VirtAddr::new_truncate(start),
+        frame: PhysAddr::new(frame),
+        size: 0x1000,
+        flags
+    };
+
+    let run = MappingRun::from(mapping(0x1000, 0x5000));
+    assert!(run.continues(&mapping(0x2000, 0x6000)));
+    assert!(!run.continues(&mapping(0x2000, 0x7000)));
+
+    // the end of the lower half is not canonical, the higher half does not continue it
+    let run = MappingRun::from(mapping(0x7fff_ffff_f000, 0x5000));
+    assert_eq!(run.end().as_u64(), 0xffff_8000_0000_0000);
+    assert!(!run.continues(&mapping(0xffff_8000_0000_0000, 0x6000)));
+
+    // the last page of the address space
+    let run = MappingRun::from(mapping(0xffff_ffff_ffff_f000, 0x5000));
+    assert_eq!(run.end().as_u64(), 0);
+    assert!(!run.continues(&mapping(0, 0x6000)));
+}
@@ -0,0 +1,103 @@
+/*
+Kernel stack allocator
+
+Each kernel stack is a virtual memory area of kind Stack: the lowest page of the area is
+left unmapped as guard page, the rest is mapped to newly allocated frames.
+
+    | guard page | stack memory ... | top
+      unmapped     grows downwards ^ initial stack pointer
+
+A stack overflow writes into the guard page and causes a page fault instead of silently
+overwriting the memory below the stack. The page fault cannot be delivered on the
+overflowed stack, so it becomes a double fault, and the double fault handler uses
+guard_page_owner to report which stack overflowed.
+
+The stacks are used for the interrupt stack table (see gdt::init_stacks) and can be used
+for kernel threads. A stack is unmapped and its frames are freed when it is dropped
+*/
+use x86_64::{
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr
+};
+
+use super::vma::{self, AreaKind, VirtualArea, VMA_MANAGER};
+
+const GUARD_PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+
+pub struct KernelStack {
+    area: VirtualArea   // the area containing guard page and stack memory
+}
+
+impl KernelStack {
+    /*
+    Allocate a stack of size bytes (rounded up to whole pages) with a guard page below it
+    The name is reported when the stack overflows.
+    Return None if there is not enough virtual or physical memory
+    */
+    pub fn new(size: u64, name: &'static str) -> Option<Self> {
+        let size = (size + Size4KiB::SIZE - 1) & !(Size4KiB::SIZE - 1);
+        let area = vma::reserve(size + GUARD_PAGE_SIZE, AreaKind::Stack, name)?;
+        let stack = KernelStack { area };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        // on error, the stack is dropped and the mapped pages are freed
+        vma::map_region(stack.bottom(), size, flags).ok()?;
+        Some(stack)
+    }
+
+    // the initial stack pointer (the stack grows downwards)
+    pub fn top(&self) -> VirtAddr {
+        self.area.end()
+    }
+
+    // the lowest address of the stack memory, the guard page is directly below it
+    pub fn bottom(&self) -> VirtAddr {
+        self.area.start + GUARD_PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.area.size - GUARD_PAGE_SIZE
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.area.name
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        vma::release(self.area.start);
+    }
+}
+
+
+/*
+Return the name of the stack whose guard page contains addr
+
+Called from the page fault and double fault handlers, so the lock is only tried: if it is held,
+the fault cannot be attributed to a stack
+*/
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let area = VMA_MANAGER.try_lock()?.find(addr)?;
+    if area.kind == AreaKind::Stack && addr < area.start + GUARD_PAGE_SIZE {
+        Some(area.name)
+    } else {
+        None
+    }
+}
+
+/*
+Return the bottom and top of the kernel stack containing addr (e.g. a stack pointer)
+
+The lock is only tried, so this can be called with VMA_MANAGER held: it returns None
+if the lock is held or addr is not in a kernel stack
+*/
+pub fn stack_bounds(addr: VirtAddr) -> Option<(VirtAddr, VirtAddr)> {
+    let area = VMA_MANAGER.try_lock()?.find(addr)?;
+    if area.kind == AreaKind::Stack && addr >= area.start + GUARD_PAGE_SIZE {
+        Some((area.start + GUARD_PAGE_SIZE, area.end()))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,308 @@
+/*
+Kernel virtual address space manager
+
+The kernel virtual memory from KERNEL_AREA_START to KERNEL_AREA_END is divided into
+virtual memory areas (VMA). A subsystem reserves an area for its heap, stacks or MMIO
+registers, and maps pages inside its own area with map_region, instead of
+picking a constant virtual address.
+
+Reserving an area only records the range, pages are mapped separately, so an area can be
+larger than the memory currently used (e.g. the heap reserves space to grow).
+A demand paged area is not mapped up front at all: the first access to each page causes a
+page fault, and the page fault handler maps a zeroed frame (see handle_page_fault).
+
+The areas are kept in a fixed size array sorted by start address, so the manager never
+allocates heap memory and can be used before the heap is initialized.
+Lock order: VMA_MANAGER is locked before MEMORY_MANAGER
+*/
+use core::fmt;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{mapper::MapToError, PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    VirtAddr
+};
+
+use super::MEMORY_MANAGER;
+
+// the kernel virtual memory managed by VMA_MANAGER (level 4 entries 128 to 159, 16 TiB)
+// the bootloader does not map anything in this range
+pub const KERNEL_AREA_START: u64 = 0x_4000_0000_0000;
+pub const KERNEL_AREA_END: u64 = 0x_5000_0000_0000;
+
+const MAX_AREAS: usize = 64;
+
+
+// the purpose of a virtual memory area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    Heap,
+    Stack,
+    Mmio,
+    Other
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualArea {
+    pub start: VirtAddr,
+    pub size: u64,
+    pub kind: AreaKind,
+    pub name: &'static str,
+    // the flags of pages mapped on page fault, None if the area is not demand paged
+    pub demand_flags: Option<PageTableFlags>
+}
+
+impl VirtualArea {
+    // the end address (exclusive)
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    // check whether [start, start + size) is inside the area
+    pub fn contains_range(&self, start: VirtAddr, size: u64) -> bool {
+        self.start <= start && start.as_u64() + size <= self.end().as_u64()
+    }
+}
+
+impl fmt::Display for VirtualArea {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}-{:#x} {:?} {} ({} KiB)",
+            self.start.as_u64(), self.end().as_u64(), self.kind, self.name, self.size / 1024)?;
+        if self.demand_flags.is_some() {
+            write!(f, " demand paged")?;
+        }
+        Ok(())
+    }
+}
+
+
+pub struct VirtualAreaManager {
+    start: u64,
+    end: u64,
+    areas: [Option<VirtualArea>; MAX_AREAS],    // the first count entries, sorted by start
+    count: usize
+}
+
+impl VirtualAreaManager {
+    // create a manager of the virtual memory from start to end
+    pub const fn new(start: u64, end: u64) -> Self {
+        VirtualAreaManager {
+            start,
+            end,
+            areas: [None; MAX_AREAS],
+            count: 0
+        }
+    }
+
+    /*
+    Reserve an area of size bytes (rounded up to whole pages) at the lowest free address
+
+    Areas of at least 2 MiB are aligned to 2 MiB, so that they can be mapped with huge pages.
+    Return None if there is no free range large enough or the area table is full
+    */
+    pub fn reserve(&mut self, size: u64, kind: AreaKind, name: &'static str) -> Option<VirtualArea> {
+        let size = align_up(size.max(1), Size4KiB::SIZE);
+        let align = if size >= Size2MiB::SIZE { Size2MiB::SIZE } else { Size4KiB::SIZE };
+
+        // first fit: try the gap before each area, then the gap after the last area
+        let mut candidate = align_up(self.start, align);
+        for area in self.iter() {
+            if candidate + size <= area.start.as_u64() {
+                break;
+            }
+            candidate = candidate.max(align_up(area.end().as_u64(), align));
+        }
+        self.reserve_at(VirtAddr::new(candidate), size, kind, name)
+    }
+
+    // reserve an area at a fixed page aligned address, return None if it overlaps another area
+    pub fn reserve_at(&mut self, start: VirtAddr, size: u64, kind: AreaKind, name: &'static str)
+        -> Option<VirtualArea>
+    {
+        let size = align_up(size.max(1), Size4KiB::SIZE);
+        let end = start.as_u64().checked_add(size)?;
+        if !start.is_aligned(Size4KiB::SIZE) || start.as_u64() < self.start || end > self.end
+            || self.count == MAX_AREAS {
+            return None;
+        }
+
+        // the index of the first area after the new area
+        let index = self.iter().position(|area| area.start.as_u64() >= end).unwrap_or(self.count);
+        // the area before must end before the new area starts
+        if index > 0 && self.areas[index - 1].unwrap().end() > start {
+            return None;
+        }
+
+        let area = VirtualArea { start, size, kind, name, demand_flags: None };
+        self.areas[index..=self.count].rotate_right(1);
+        self.areas[index] = Some(area);
+        self.count += 1;
+        Some(area)
+    }
+
+    // remove the area starting at start
+    pub fn release(&mut self, start: VirtAddr) -> Option<VirtualArea> {
+        let index = self.iter().position(|area| area.start == start)?;
+        let area = self.areas[index].take();
+        self.areas[index..self.count].rotate_left(1);
+        self.count -= 1;
+        area
+    }
+
+    // find the area containing addr
+    pub fn find(&self, addr: VirtAddr) -> Option<VirtualArea> {
+        self.iter().find(|area| area.contains(addr))
+    }
+
+    // make the area starting at start demand paged, pages are mapped with flags on page fault
+    pub fn set_demand_paged(&mut self, start: VirtAddr, flags: PageTableFlags) -> Option<VirtualArea> {
+        let area = self.areas[..self.count].iter_mut()
+            .flatten()
+            .find(|area| area.start == start)?;
+        area.demand_flags = Some(flags | PageTableFlags::PRESENT);
+        Some(*area)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = VirtualArea> + '_ {
+        self.areas[..self.count].iter().map(|area| area.unwrap())
+    }
+}
+
+
+pub static VMA_MANAGER: spin::Mutex<VirtualAreaManager> =
+    spin::Mutex::new(VirtualAreaManager::new(KERNEL_AREA_START, KERNEL_AREA_END));
+
+// reserve an area of kernel virtual memory
+pub fn reserve(size: u64, kind: AreaKind, name: &'static str) -> Option<VirtualArea> {
+    VMA_MANAGER.lock().reserve(size, kind, name)
+}
+
+// reserve an area of kernel virtual memory at a fixed address
+pub fn reserve_at(start: VirtAddr, size: u64, kind: AreaKind, name: &'static str) -> Option<VirtualArea> {
+    VMA_MANAGER.lock().reserve_at(start, size, kind, name)
+}
+
+// reserve an area of kernel virtual memory that is mapped with flags on first access
+pub fn reserve_demand_paged(size: u64, kind: AreaKind, name: &'static str, flags: PageTableFlags)
+    -> Option<VirtualArea>
+{
+    let mut vma_manager = VMA_MANAGER.lock();
+    let area = vma_manager.reserve(size, kind, name)?;
+    vma_manager.set_demand_paged(area.start, flags)
+}
+
+// unmap all pages in the area starting at start and release it
+pub fn release(start: VirtAddr) {
+    let mut vma_manager = VMA_MANAGER.lock();
+    let area = vma_manager.find(start)
+        .filter(|area| area.start == start)
+        .expect("no virtual memory area at the address");
+    unmap_pages(area.start, area.size);
+    vma_manager.release(start);
+}
+
+// the area containing addr
+pub fn find_area(addr: VirtAddr) -> Option<VirtualArea> {
+    VMA_MANAGER.lock().find(addr)
+}
+
+/*
+Map the pages from start to start + size to newly allocated frames with flags
+The range must be inside a reserved area
+*/
+pub fn map_region(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+    let vma_manager = VMA_MANAGER.lock();
+    check_reserved(&vma_manager, start, size);
+    MEMORY_MANAGER.lock()
+        .as_mut()
+        .expect("memory manager not initialized")
+        .map_range(start, size, flags)
+}
+
+/*
+Unmap the pages from start to start + size and free their frames
+Pages that are not mapped are skipped. The range must be inside a reserved area
+*/
+pub fn unmap_region(start: VirtAddr, size: u64) {
+    let vma_manager = VMA_MANAGER.lock();
+    check_reserved(&vma_manager, start, size);
+    unmap_pages(start, size);
+}
+
+/*
+Handle a page fault at addr, return true if the faulting access can be retried
+
+A fault is handled if the page is not present and addr is in a demand paged area that allows
+the access. Then a zeroed frame is mapped at the page. Every other fault is fatal.
+
+The locks are only tried, since the fault may happen while they are held (e.g. in the
+frame allocator), then the fault cannot be handled either
+*/
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // the page is present, so the access violates its flags
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+
+    let flags = match VMA_MANAGER.try_lock().and_then(|vma_manager| vma_manager.find(addr)) {
+        Some(VirtualArea { demand_flags: Some(flags), .. }) => flags,
+        _ => return false
+    };
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !flags.contains(PageTableFlags::WRITABLE) {
+        return false;
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && flags.contains(PageTableFlags::NO_EXECUTE) {
+        return false;
+    }
+
+    match MEMORY_MANAGER.try_lock() {
+        Some(mut memory_manager) => match memory_manager.as_mut() {
+            Some(memory_manager) => memory_manager.map_zeroed_page(addr, flags).is_ok(),
+            None => false
+        },
+        None => false
+    }
+}
+
+fn check_reserved(vma_manager: &VirtualAreaManager, start: VirtAddr, size: u64) {
+    let reserved = vma_manager.find(start)
+        .map_or(false, |area| area.contains_range(start, size));
+    assert!(reserved, "{:#x}-{:#x} is not in a reserved virtual memory area",
+        start.as_u64(), start.as_u64() + size);
+}
+
+fn unmap_pages(start: VirtAddr, size: u64) {
+    MEMORY_MANAGER.lock()
+        .as_mut()
+        .expect("memory manager not initialized")
+        .unmap_range(start, size);
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+
+// test cases
+#[test_case]
+fn test_reserve_without_overlap() {
+    let mut manager = VirtualAreaManager::new(0x10_0000, 0x100_0000);
+    let a = manager.reserve(0x1800, AreaKind::Stack, "a").unwrap();
+    let b = manager.reserve(0x20_0000, AreaKind::Heap, "b").unwrap();
+    let c = manager.reserve(0x1000, AreaKind::Mmio, "c").unwrap();
+    assert_eq!(a.start.as_u64(), 0x10_0000);
+    assert_eq!(a.size, 0x2000);
+    assert_eq!(b.start.as_u64(), 0x20_0000);    // aligned to 2 MiB
+    assert_eq!(c.start.as_u64(), 0x10_2000);    // fills the gap before b
+    assert!(manager.reserve_at(VirtAddr::new(0x30_0000), 0x1000, AreaKind::Other, "d").is_none());
+    assert_eq!(manager.find(VirtAddr::new(0x30_0000)).unwrap().name, "b");
+
+    // a released range can be reserved again
+    manager.release(a.start).unwrap();
+    assert!(manager.find(a.start).is_none());
+    assert_eq!(manager.reserve(0x1000, AreaKind::Stack, "e").unwrap().start, a.start);
+    assert!(manager.reserve(0x100_0000, AreaKind::Other, "f").is_none());
+}
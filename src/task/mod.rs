@@ -7,6 +7,7 @@ use alloc::boxed::Box;
 pub mod simple_executor;    // a dummy executor for testing
 pub mod executor;      // the task executor
 pub mod keyboard;    // handle keyboard scancodes.
+pub mod timer;   // sleep, interval and timeout futures
 
 // a unique id for a task
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
@@ -0,0 +1,250 @@
+/*
+Timer futures for async tasks
+
+A task waits for time to pass with sleep or sleep_until. A pending Sleep registers its
+deadline and the waker of its task in a deadline heap, and the timer interrupt handler
+wakes the tasks whose deadline has passed (wake_expired). Deadlines are checked once per
+tick, and Instant::now is the time of the last tick, which can be up to a tick in the past.
+So sleep adds one tick to its deadline: a sleep takes at least its duration and at most
+two ticks longer.
+
+The interrupt handler only pops entries from the heap and wakes them, it never allocates.
+A Sleep removes its entry when it is dropped, so the interrupt handler never holds the
+last reference to a waker and never frees memory either.
+
+Based on Sleep:
+- interval: a stream that yields at a fixed period
+- timeout: run a future with a time limit
+*/
+use alloc::boxed::Box;
+use alloc::collections::BinaryHeap;
+use core::cmp::{Ordering, Reverse};
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{self, AtomicU64};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use futures_util::stream::Stream;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::time::{self, Instant};
+
+
+// a registered deadline, ordered by deadline
+struct TimerEntry {
+    deadline: Instant,
+    id: u64,    // the id of the Sleep, to remove the entry
+    waker: Waker
+}
+
+impl PartialEq for TimerEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TimerEntry {}
+
+impl PartialOrd for TimerEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deadline, self.id).cmp(&(other.deadline, other.id))
+    }
+}
+
+lazy_static! {
+    // the heap of registered deadlines, the earliest deadline is at the top
+    static ref TIMERS: Mutex<BinaryHeap<Reverse<TimerEntry>>> = Mutex::new(BinaryHeap::new());
+}
+
+
+/*
+Wake the tasks whose deadline has passed, called by the timer interrupt handler
+If a task is registering a deadline at the moment, the expired deadlines are
+woken on the next tick
+*/
+pub(crate) fn wake_expired() {
+    let mut timers = match TIMERS.try_lock() {
+        Some(timers) => timers,
+        None => return
+    };
+    let now = Instant::now();
+    while timers.peek().map_or(false, |Reverse(entry)| entry.deadline <= now) {
+        if let Some(Reverse(entry)) = timers.pop() {
+            entry.waker.wake();
+        }
+    }
+}
+
+// the number of registered deadlines
+pub fn pending_timers() -> usize {
+    TIMERS.lock().len()
+}
+
+
+// a future that completes at deadline, see sleep and sleep_until
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    registered: bool
+}
+
+impl Sleep {
+    fn new(deadline: Instant) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Sleep {
+            deadline,
+            id: NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed),
+            registered: false
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    // remove the registered entry of this Sleep
+    fn unregister(&mut self, timers: &mut BinaryHeap<Reverse<TimerEntry>>) {
+        if self.registered {
+            timers.retain(|Reverse(entry)| entry.id != self.id);
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let sleep = self.get_mut();
+        let mut timers = TIMERS.lock();
+        if sleep.is_elapsed() {
+            sleep.unregister(&mut timers);
+            return Poll::Ready(());
+        }
+
+        // the task may have moved to another waker since the last poll
+        sleep.unregister(&mut timers);
+        timers.push(Reverse(TimerEntry {
+            deadline: sleep.deadline,
+            id: sleep.id,
+            waker: cx.waker().clone()
+        }));
+        sleep.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            self.unregister(&mut TIMERS.lock());
+        }
+    }
+}
+
+// wait until duration has passed, the deadline is rounded up by a tick (see above)
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep::new(Instant::now() + duration + time::tick_period())
+}
+
+// wait until deadline, completes immediately if deadline has passed
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep::new(deadline)
+}
+
+
+/*
+A stream that yields every period, see interval
+
+The n-th item is yielded at start + n * period, and yields that time. If the task was
+too busy to take the items in time, the missed items are yielded immediately
+*/
+pub struct Interval {
+    sleep: Sleep,
+    period: Duration
+}
+
+// create an interval stream, the first item is yielded immediately
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Instant::now(), period)
+}
+
+// create an interval stream with the first item at start
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "interval period must be positive");
+    Interval { sleep: sleep_until(start), period }
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let deadline = self.sleep.deadline;
+                self.sleep = sleep_until(deadline + self.period);
+                Poll::Ready(Some(deadline))
+            },
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
+
+
+// the error of a future that did not complete in time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+// a future with a time limit, see timeout
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep
+}
+
+/*
+Run future with a time limit of duration
+Return the output of future, or Elapsed if it did not complete in time
+(future is dropped then)
+*/
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout { future: Box::pin(future), sleep: sleep(duration) }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // the future is polled first, so it can complete even at the deadline
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending
+        }
+    }
+}
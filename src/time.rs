@@ -0,0 +1,304 @@
+/*
+Kernel timekeeping
+
+The programmable interval timer (PIT) is programmed to interrupt TIMER_FREQUENCY times per
+second, and the timer interrupt handler counts the ticks. The time since boot is the sum
+of the tick periods, so it never goes backwards (a monotonic clock).
+The periodic interrupt of the RTC can be used as tick source instead of the PIT.
+
+Instant is a point in time since boot with nanosecond unit (but tick precision),
+and the durations between instants are core::time::Duration.
+wall_clock combines the monotonic clock with the RTC into calendar time, and
+clocksource provides timestamps with nanosecond precision from the TSC or HPET
+*/
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::{self, InterruptIndex};
+
+// CMOS real-time clock
+pub mod rtc;
+// calendar time in UTC
+pub mod wall_clock;
+// high resolution counters: the TSC and the HPET
+pub mod clocksource;
+pub mod tsc;
+pub mod hpet;
+
+// the input frequency of the PIT in Hz
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+// the requested frequency of timer interrupts in Hz
+pub const TIMER_FREQUENCY: u64 = 1000;
+// the PIT divides its input frequency by this, so the actual frequency is slightly higher
+const PIT_DIVISOR: u64 = PIT_FREQUENCY / TIMER_FREQUENCY;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_CHANNEL_2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// channel 0, low byte then high byte of the divisor, mode 2 (rate generator), binary
+const PIT_RATE_GENERATOR: u8 = 0b0011_0100;
+// channel 2, low byte then high byte of the count, mode 0 (interrupt on terminal count), binary
+const PIT_ONE_SHOT: u8 = 0b1011_0000;
+// the gate and output of channel 2 are in port B of the keyboard controller
+const PORT_B: u16 = 0x61;
+const CHANNEL_2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+// the number of ticks since init, and the sum of their periods
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static NANOS: AtomicU64 = AtomicU64::new(0);
+// whether the ticks come from the RTC instead of the PIT
+static RTC_TICKS: AtomicBool = AtomicBool::new(false);
+
+
+// the interrupt counted as clock tick
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    Rtc
+}
+
+impl TickSource {
+    // the time between two ticks
+    pub const fn period(self) -> Duration {
+        match self {
+            TickSource::Pit => Duration::from_nanos(ticks_to_nanos(1)),
+            TickSource::Rtc => Duration::from_nanos(1_000_000_000 / rtc::PERIODIC_FREQUENCY)
+        }
+    }
+}
+
+/*
+Program the PIT and read the RTC for the wall clock
+This is called by crate::init before interrupts are enabled
+*/
+pub fn init() {
+    init_pit();
+    wall_clock::init();
+}
+
+// set the frequency of the PIT interrupt
+fn init_pit() {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0);
+    unsafe {
+        command.write(PIT_RATE_GENERATOR);
+        channel_0.write(PIT_DIVISOR as u8);
+        channel_0.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+/*
+Measure how much counter advances in pit_ticks periods of the PIT input clock, to calibrate
+other clocks (see tsc). The time is measured with channel 2 of the PIT (the speaker channel,
+which has no interrupt) counting down once, so the ticks of channel 0 are not affected.
+Interrupts are disabled while waiting
+*/
+pub(crate) fn pit_measure(pit_ticks: u16, counter: impl Fn() -> u64) -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut port_b: Port<u8> = Port::new(PORT_B);
+        let mut command: Port<u8> = Port::new(PIT_COMMAND);
+        let mut channel_2: Port<u8> = Port::new(PIT_CHANNEL_2);
+        unsafe {
+            // open the gate of channel 2, but keep the speaker off
+            let value = port_b.read();
+            port_b.write((value & !SPEAKER_ENABLE) | CHANNEL_2_GATE);
+            // the output is low after the count is written, and high when the count reaches 0
+            command.write(PIT_ONE_SHOT);
+            channel_2.write(pit_ticks as u8);
+            channel_2.write((pit_ticks >> 8) as u8);
+        }
+
+        let start = counter();
+        while unsafe { port_b.read() } & CHANNEL_2_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+        counter() - start
+    })
+}
+
+/*
+Select the interrupt counted as clock tick, the PIT is used until this is called
+The RTC interrupt is enabled at the active interrupt controller, so this needs to be
+called after interrupts::init_controller
+*/
+pub fn set_tick_source(source: TickSource) {
+    let use_rtc = source == TickSource::Rtc;
+    if RTC_TICKS.load(Ordering::SeqCst) == use_rtc {
+        return;
+    }
+    if use_rtc {
+        interrupts::enable_irq(InterruptIndex::Rtc);
+        rtc::set_periodic_interrupt(true);
+        RTC_TICKS.store(true, Ordering::SeqCst);
+    } else {
+        // the PIT interrupt is always enabled, its ticks are counted again
+        RTC_TICKS.store(false, Ordering::SeqCst);
+        rtc::set_periodic_interrupt(false);
+    }
+}
+
+pub fn tick_source() -> TickSource {
+    if RTC_TICKS.load(Ordering::SeqCst) {
+        TickSource::Rtc
+    } else {
+        TickSource::Pit
+    }
+}
+
+/*
+Count a tick from source, called by the PIT and RTC interrupt handlers
+Return false if source is not the tick source, then the tick is ignored
+*/
+pub(crate) fn tick(source: TickSource) -> bool {
+    if source != tick_source() {
+        return false;
+    }
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    NANOS.fetch_add(source.period().as_nanos() as u64, Ordering::Relaxed);
+    true
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// convert a number of PIT ticks to nanoseconds
+pub const fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * PIT_DIVISOR as u128 * 1_000_000_000 / PIT_FREQUENCY as u128) as u64
+}
+
+// the time between two ticks of the tick source
+pub fn tick_period() -> Duration {
+    tick_source().period()
+}
+
+// the time since init
+pub fn uptime() -> Duration {
+    Duration::from_nanos(NANOS.load(Ordering::Relaxed))
+}
+
+
+// a point in time, measured in nanoseconds since init
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(NANOS.load(Ordering::Relaxed))
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    // the time since init
+    pub const fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    // the time from earlier to self, zero if earlier is later than self
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+// print the time since init in seconds with microseconds, e.g. 12.345678, for log timestamps
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06}", self.0 / 1_000_000_000, self.0 % 1_000_000_000 / 1000)
+    }
+}
+
+
+// test the clock advances with timer interrupts
+#[test_case]
+fn test_uptime_advances() {
+    let start = Instant::now();
+    let start_ticks = ticks();
+    // hlt returns after the next interrupt, which is usually the timer
+    while ticks() < start_ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= 3 * tick_period());
+    assert!(uptime() >= start.elapsed());
+}
+
+// test instant arithmetic
+#[test_case]
+fn test_instant_arithmetic() {
+    let start = Instant::from_nanos(1_500_000_000);
+    let later = start + Duration::from_millis(250);
+    assert_eq!(later.as_nanos(), 1_750_000_000);
+    assert_eq!(later - start, Duration::from_millis(250));
+    assert_eq!(start - later, Duration::ZERO);
+    assert_eq!(later - Duration::from_millis(250), start);
+    assert_eq!(Instant::from_nanos(0).checked_sub(Duration::from_nanos(1)), None);
+    assert_eq!(ticks_to_nanos(PIT_FREQUENCY / PIT_DIVISOR), 999_847_466);
+}
+
+// test the RTC periodic interrupt can replace the PIT as tick source
+#[test_case]
+fn test_rtc_tick_source() {
+    set_tick_source(TickSource::Rtc);
+    let start = Instant::now();
+    let start_ticks = ticks();
+    while ticks() < start_ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    assert!(start.elapsed() >= 10 * TickSource::Rtc.period());
+
+    set_tick_source(TickSource::Pit);
+    let start_ticks = ticks();
+    while ticks() < start_ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,122 @@
+/*
+Clock sources
+
+A clock source is a free running counter with a fixed frequency, read without interrupts.
+The PIT ticks only advance the clock once per millisecond, a clock source has nanosecond
+precision, so it is used to timestamp events and to benchmark code paths:
+- tsc: the time stamp counter of the CPU, calibrated at boot against the HPET or the PIT.
+  It is the fastest to read, but only counts at a fixed rate if it is invariant
+- hpet: the main counter of the high precision event timer, found with ACPI
+- TickClock: the tick counter of time, when there is neither
+
+init selects the best source: an invariant TSC, then the HPET, then any TSC, then the ticks.
+The timestamps of nanos are in nanoseconds since boot, counted by the clock source
+from the time (uptime) it was selected
+*/
+use conquer_once::spin::OnceCell;
+use core::time::Duration;
+
+use super::{hpet, tsc};
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    // the frequency of the counter in Hz
+    fn frequency(&self) -> u64;
+
+    // the current value of the counter, it never decreases
+    fn read(&self) -> u64;
+
+    // convert a number of counts to nanoseconds
+    fn counts_to_nanos(&self, counts: u64) -> u64 {
+        (counts as u128 * NANOS_PER_SECOND / self.frequency() as u128) as u64
+    }
+}
+
+
+// the tick counter of time as a clock source, it has tick precision only
+pub struct TickClock;
+
+impl ClockSource for TickClock {
+    fn name(&self) -> &'static str {
+        "ticks"
+    }
+
+    fn frequency(&self) -> u64 {
+        NANOS_PER_SECOND as u64
+    }
+
+    fn read(&self) -> u64 {
+        super::uptime().as_nanos() as u64
+    }
+}
+
+
+// the selected clock source, and its counter and the uptime when it was selected
+struct Selected {
+    source: &'static dyn ClockSource,
+    base_count: u64,
+    base_nanos: u64
+}
+
+static SELECTED: OnceCell<Selected> = OnceCell::uninit();
+
+/*
+Find the HPET, calibrate the TSC and select the clock source used by nanos
+This maps the HPET registers, so it needs the memory manager. Calling it again
+returns the selected source
+*/
+pub fn init() -> &'static dyn ClockSource {
+    if let Ok(selected) = SELECTED.try_get() {
+        return selected.source;
+    }
+    let hpet = hpet::init();
+    let tsc = tsc::init(hpet);
+    let source: &'static dyn ClockSource = match (tsc, hpet) {
+        (Some(tsc), _) if tsc.is_invariant() => tsc,
+        (_, Some(hpet)) => hpet,
+        (Some(tsc), None) => tsc,
+        (None, None) => &TickClock
+    };
+
+    let _ = SELECTED.try_init_once(|| Selected {
+        source,
+        base_count: source.read(),
+        base_nanos: super::uptime().as_nanos() as u64
+    });
+    current()
+}
+
+// the selected clock source, TickClock before init
+pub fn current() -> &'static dyn ClockSource {
+    match SELECTED.try_get().ok() {
+        Some(selected) => selected.source,
+        None => &TickClock
+    }
+}
+
+// the time since boot in nanoseconds, with the precision of the selected clock source
+pub fn nanos() -> u64 {
+    match SELECTED.try_get().ok() {
+        Some(selected) => {
+            let counts = selected.source.read().wrapping_sub(selected.base_count);
+            selected.base_nanos + selected.source.counts_to_nanos(counts)
+        },
+        None => TickClock.read()
+    }
+}
+
+// the time since boot, see nanos
+pub fn timestamp() -> Duration {
+    Duration::from_nanos(nanos())
+}
+
+// run f and measure its duration with the selected clock source
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Duration) {
+    let start = nanos();
+    let result = f();
+    (result, Duration::from_nanos(nanos() - start))
+}
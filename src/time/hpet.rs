@@ -0,0 +1,118 @@
+/*
+High precision event timer (HPET)
+
+The HPET has a main counter that counts up at a fixed frequency (at least 10 MHz), and
+comparators that can send interrupts. Only the main counter is used, as clock source
+and to calibrate the TSC.
+
+Its registers are memory mapped at the address in the ACPI table "HPET" (see acpi),
+they are mapped with memory::map_mmio:
+- the general capabilities register has the counter period in femtoseconds, and whether
+  the counter is 64-bit
+- the counter runs while the enable bit of the general configuration register is set.
+  The legacy replacement bit is left clear, so the PIT and RTC interrupts are not affected
+
+A 32-bit counter overflows after a few minutes, so only HPETs with a 64-bit counter are used
+*/
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+use super::clocksource::ClockSource;
+use crate::acpi;
+use crate::memory::{self, MmioRegion};
+
+// the base address in the HPET table is a generic address structure after the header
+// and the event timer block id, its address space id is 0 for memory
+const TABLE_ADDRESS_SPACE: u64 = 40;
+const TABLE_BASE_ADDRESS: u64 = 44;
+const SYSTEM_MEMORY: u8 = 0;
+
+// registers (offsets)
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+const REGISTERS_SIZE: usize = 0x400;
+
+const COUNTER_64_BIT: u64 = 1 << 13;           // capabilities
+const ENABLE: u64 = 1 << 0;                    // configuration
+// the period is at most 100 ns
+const MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+const FEMTOS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+
+pub struct Hpet {
+    regs: MmioRegion,
+    frequency: u64
+}
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+impl Hpet {
+    // map the HPET at base and start its main counter
+    fn new(base: PhysAddr) -> Option<Self> {
+        let regs = memory::map_mmio(base, REGISTERS_SIZE)?;
+        let capabilities = regs.read::<u64>(CAPABILITIES);
+        let period = capabilities >> 32;
+        if capabilities & COUNTER_64_BIT == 0 || period == 0 || period > MAX_PERIOD_FEMTOS {
+            return None;
+        }
+
+        let configuration = regs.read::<u64>(CONFIGURATION);
+        regs.write::<u64>(CONFIGURATION, configuration | ENABLE);
+        Some(Hpet { regs, frequency: FEMTOS_PER_SECOND / period })
+    }
+
+    pub fn base_address(&self) -> PhysAddr {
+        self.regs.phys_addr()
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.regs.read::<u64>(MAIN_COUNTER)
+    }
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        self.counter()
+    }
+}
+
+
+// the physical address of the HPET registers in the ACPI table
+pub fn find() -> Option<PhysAddr> {
+    let table = acpi::find_table(b"HPET")?;
+    let (address_space, base) = unsafe {
+        (acpi::read_phys::<u8>(table + TABLE_ADDRESS_SPACE), acpi::read_phys::<u64>(table + TABLE_BASE_ADDRESS))
+    };
+    if address_space == SYSTEM_MEMORY && base != 0 {
+        Some(PhysAddr::new(base))
+    } else {
+        None
+    }
+}
+
+/*
+Find, map and enable the HPET
+Return None if there is no usable HPET. Calling it again returns the same HPET
+*/
+pub fn init() -> Option<&'static Hpet> {
+    if let Ok(hpet) = HPET.try_get() {
+        return Some(hpet);
+    }
+    let hpet = Hpet::new(find()?)?;
+    let _ = HPET.try_init_once(|| hpet);
+    HPET.try_get().ok()
+}
+
+// the HPET, None before init or without HPET
+pub fn get() -> Option<&'static Hpet> {
+    HPET.try_get().ok()
+}
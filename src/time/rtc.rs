@@ -0,0 +1,236 @@
+/*
+CMOS real-time clock (RTC)
+
+The RTC keeps the calendar time (in UTC under QEMU and most firmware) while the computer is
+off. Its registers are read through the CMOS index (0x70) and data (0x71) ports:
+- the time registers are binary or BCD, and the hour is 24-hour or 12-hour with a PM bit,
+  depending on status register B
+- the registers are updated once per second, and are inconsistent while the update is in
+  progress. read waits for the end of the update, and repeats reading until two
+  reads are the same
+
+The RTC can also send a periodic interrupt (IRQ 8) at 32768 >> (rate - 1) Hz, which is
+acknowledged by reading status register C. time uses it as an alternative tick source
+*/
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::wall_clock::DateTime;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+// time registers
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+// not standard, but the century register of QEMU and most firmware (see ACPI FADT)
+const CENTURY: u8 = 0x32;
+
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;     // status A
+const PERIODIC_INTERRUPT: u8 = 1 << 6;     // status B
+const HOUR_FORMAT_24: u8 = 1 << 1;         // status B
+const BINARY_MODE: u8 = 1 << 2;            // status B
+const HOUR_PM: u8 = 1 << 7;                // hour register in 12-hour format
+
+// the rate of the periodic interrupt, 32768 >> (6 - 1) = 1024 Hz
+pub const PERIODIC_RATE: u8 = 6;
+pub const PERIODIC_FREQUENCY: u64 = 32768 >> (PERIODIC_RATE - 1);
+
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.address.write(register);
+            self.data.write(value);
+        }
+    }
+}
+
+// the CMOS ports are locked with interrupts disabled, since the RTC interrupt handler uses them
+static CMOS: spin::Mutex<Cmos> = spin::Mutex::new(Cmos {
+    address: Port::new(CMOS_ADDRESS),
+    data: Port::new(CMOS_DATA)
+});
+
+
+// the raw time registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8
+}
+
+impl Registers {
+    fn read(cmos: &mut Cmos) -> Self {
+        while cmos.read(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        Registers {
+            second: cmos.read(SECONDS),
+            minute: cmos.read(MINUTES),
+            hour: cmos.read(HOURS),
+            day: cmos.read(DAY),
+            month: cmos.read(MONTH),
+            year: cmos.read(YEAR),
+            century: cmos.read(CENTURY)
+        }
+    }
+
+    /*
+    Convert the registers in the format of status_b
+    Return None if they are not a valid date and time from 1970 on, e.g. after the
+    CMOS battery failed
+    */
+    fn to_date_time(self, status_b: u8) -> Option<DateTime> {
+        let binary = status_b & BINARY_MODE != 0;
+        let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+        let hour = if status_b & HOUR_FORMAT_24 != 0 {
+            convert(self.hour)
+        } else {
+            hour_from_12(convert(self.hour & !HOUR_PM), self.hour & HOUR_PM != 0)
+        };
+        let century = convert(self.century);
+        // assume the 21st century if there is no century register
+        let century = if (19..=21).contains(&century) { century as u16 } else { 20 };
+
+        let date_time = DateTime {
+            year: century * 100 + convert(self.year) as u16,
+            month: convert(self.month),
+            day: convert(self.day),
+            hour,
+            minute: convert(self.minute),
+            second: convert(self.second),
+            nanosecond: 0
+        };
+        let valid = date_time.year >= 1970
+            && (1..=12).contains(&date_time.month)
+            && (1..=31).contains(&date_time.day)
+            && date_time.hour < 24 && date_time.minute < 60 && date_time.second < 60;
+        if valid { Some(date_time) } else { None }
+    }
+}
+
+// convert a binary coded decimal (e.g. 0x59 for 59) to binary
+pub const fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// convert a 12-hour clock hour (1 to 12) to 24-hour clock
+pub const fn hour_from_12(hour: u8, pm: bool) -> u8 {
+    match (hour % 12, pm) {
+        (hour, false) => hour,
+        (hour, true) => hour + 12
+    }
+}
+
+/*
+Read the current date and time from the RTC, with second precision
+Return None if the RTC registers do not hold a valid date and time
+*/
+pub fn read() -> Option<DateTime> {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        // an update may happen between two register reads, read until they agree
+        let mut registers = Registers::read(&mut cmos);
+        loop {
+            let again = Registers::read(&mut cmos);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        let status_b = cmos.read(STATUS_B);
+        registers.to_date_time(status_b)
+    })
+}
+
+
+// enable or disable the periodic interrupt at PERIODIC_FREQUENCY
+pub fn set_periodic_interrupt(enabled: bool) {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & 0xF0) | PERIODIC_RATE);
+        let status_b = cmos.read(STATUS_B);
+        if enabled {
+            cmos.write(STATUS_B, status_b | PERIODIC_INTERRUPT);
+        } else {
+            cmos.write(STATUS_B, status_b & !PERIODIC_INTERRUPT);
+        }
+        // clear an interrupt that is already pending, otherwise the RTC sends no more
+        cmos.read(STATUS_C);
+    });
+}
+
+// acknowledge an RTC interrupt, called by the RTC interrupt handler
+pub(crate) fn acknowledge_interrupt() {
+    CMOS.lock().read(STATUS_C);
+}
+
+
+// test BCD and 12-hour clock conversion
+#[test_case]
+fn test_register_conversion() {
+    assert_eq!(bcd_to_binary(0x59), 59);
+    assert_eq!(bcd_to_binary(0x00), 0);
+    assert_eq!(hour_from_12(12, false), 0);
+    assert_eq!(hour_from_12(12, true), 12);
+    assert_eq!(hour_from_12(1, true), 13);
+
+    let registers = Registers {
+        second: 0x30, minute: 0x45, hour: HOUR_PM | 0x11,
+        day: 0x29, month: 0x02, year: 0x24, century: 0x20
+    };
+    let date_time = registers.to_date_time(0).unwrap();
+    assert_eq!((date_time.year, date_time.month, date_time.day), (2024, 2, 29));
+    assert_eq!((date_time.hour, date_time.minute, date_time.second), (23, 45, 30));
+
+    let binary = Registers { hour: 23, minute: 45, second: 30, day: 29, month: 2, year: 24, century: 0 };
+    assert_eq!(binary.to_date_time(BINARY_MODE | HOUR_FORMAT_24), Some(date_time));
+
+    // garbage registers, e.g. after a CMOS battery failure, are rejected
+    let invalid = [
+        Registers { day: 0, ..binary },
+        Registers { month: 13, ..binary },
+        Registers { hour: 24, ..binary },
+        Registers { year: 69, century: 19, ..binary },
+        Registers { second: 0xFF, ..binary }
+    ];
+    for registers in invalid {
+        assert_eq!(registers.to_date_time(BINARY_MODE | HOUR_FORMAT_24), None);
+    }
+}
+
+// test the RTC of QEMU has a valid date
+#[test_case]
+fn test_read_rtc() {
+    let now = read().expect("invalid RTC time");
+    assert!(now.year >= 2020);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
@@ -0,0 +1,164 @@
+/*
+Time stamp counter (TSC)
+
+The TSC counts the cycles of the CPU since reset and is read with rdtsc, which takes only
+a few cycles. Its frequency is not reported reliably, so init measures it against a clock
+with a known frequency: the HPET if there is one, otherwise channel 2 of the PIT.
+
+Older CPUs change the TSC rate with the CPU frequency and stop it in deep sleep states.
+Invariant TSCs (CPUID.80000007h:EDX bit 8) count at a constant rate, and only they
+are preferred as clock source (see clocksource)
+
+Note: rdtsc is not serializing, the CPU may execute it before earlier instructions
+are finished. This is well below the precision needed for timestamps
+*/
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+use super::clocksource::ClockSource;
+use super::hpet::Hpet;
+use super::PIT_FREQUENCY;
+
+// measure for 10 ms, the best of CALIBRATION_ROUNDS measurements is used
+const CALIBRATION_MILLIS: u64 = 10;
+const CALIBRATION_ROUNDS: usize = 3;
+
+const EXTENDED_FEATURES_LEAF: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT_LEAF: u32 = 0x8000_0007;
+
+
+// the clock the TSC was calibrated against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Pit,
+    Hpet
+}
+
+pub struct Tsc {
+    frequency: u64,
+    invariant: bool,
+    reference: Reference
+}
+
+static TSC: OnceCell<Tsc> = OnceCell::uninit();
+
+impl Tsc {
+    pub fn is_invariant(&self) -> bool {
+        self.invariant
+    }
+
+    pub fn reference(&self) -> Reference {
+        self.reference
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        read()
+    }
+}
+
+
+// __cpuid is only unsafe in older Rust versions
+#[allow(unused_unsafe)]
+fn cpuid_edx(leaf: u32) -> u32 {
+    unsafe { __cpuid(leaf).edx }
+}
+
+// check whether the CPU has a TSC (CPUID.01h:EDX bit 4)
+pub fn is_supported() -> bool {
+    cpuid_edx(1) & (1 << 4) != 0
+}
+
+// check whether the TSC counts at a constant rate (CPUID.80000007h:EDX bit 8)
+pub fn is_invariant() -> bool {
+    #[allow(unused_unsafe)]
+    let max_extended_leaf = unsafe { __cpuid(EXTENDED_FEATURES_LEAF).eax };
+    max_extended_leaf >= ADVANCED_POWER_MANAGEMENT_LEAF
+        && cpuid_edx(ADVANCED_POWER_MANAGEMENT_LEAF) & (1 << 8) != 0
+}
+
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+
+// the TSC frequency measured against channel 2 of the PIT
+fn calibrate_with_pit() -> u64 {
+    let pit_ticks = PIT_FREQUENCY * CALIBRATION_MILLIS / 1000;
+    let cycles = super::pit_measure(pit_ticks as u16, read);
+    cycles * PIT_FREQUENCY / pit_ticks
+}
+
+// the TSC frequency measured against the HPET
+fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    let hpet_counts = hpet.frequency() * CALIBRATION_MILLIS / 1000;
+    let (cycles, counts) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let hpet_start = hpet.read();
+        let tsc_start = read();
+        let mut hpet_end = hpet_start;
+        while hpet_end - hpet_start < hpet_counts {
+            core::hint::spin_loop();
+            hpet_end = hpet.read();
+        }
+        (read() - tsc_start, hpet_end - hpet_start)
+    });
+    (cycles as u128 * hpet.frequency() as u128 / counts as u128) as u64
+}
+
+// the TSC frequency measured against hpet, or the PIT if there is no HPET, 0 if it failed
+fn measure_frequency(hpet: Option<&Hpet>) -> u64 {
+    // an interrupt (e.g. SMI) during a measurement makes it longer, take the shortest
+    let frequency = match hpet {
+        Some(hpet) => (0..CALIBRATION_ROUNDS).map(|_| calibrate_with_hpet(hpet)).min(),
+        None => (0..CALIBRATION_ROUNDS).map(|_| calibrate_with_pit()).min()
+    };
+    frequency.unwrap_or(0)
+}
+
+/*
+Measure the TSC frequency against reference, without changing the calibration of init
+This compares the references, e.g. in tests. Return None if the CPU has no TSC,
+or reference is Hpet and hpet::init found no HPET
+*/
+pub fn calibrate(reference: Reference) -> Option<u64> {
+    if !is_supported() {
+        return None;
+    }
+    let frequency = match reference {
+        Reference::Hpet => measure_frequency(Some(super::hpet::get()?)),
+        Reference::Pit => measure_frequency(None)
+    };
+    Some(frequency).filter(|frequency| *frequency > 0)
+}
+
+/*
+Calibrate the TSC against hpet, or the PIT if there is no HPET
+Return None if the CPU has no TSC. Calling it again returns the first calibration
+*/
+pub fn init(hpet: Option<&Hpet>) -> Option<&'static Tsc> {
+    if !is_supported() {
+        return None;
+    }
+    if let Ok(tsc) = TSC.try_get() {
+        return Some(tsc);
+    }
+
+    let reference = if hpet.is_some() { Reference::Hpet } else { Reference::Pit };
+    let frequency = Some(measure_frequency(hpet)).filter(|frequency| *frequency > 0)?;
+    let _ = TSC.try_init_once(|| Tsc { frequency, invariant: is_invariant(), reference });
+    TSC.try_get().ok()
+}
+
+// the calibrated TSC, None before init or without TSC
+pub fn get() -> Option<&'static Tsc> {
+    TSC.try_get().ok()
+}
@@ -0,0 +1,151 @@
+/*
+Wall-clock time
+
+The RTC only has second precision and is slow to read, so it is read once by init.
+The wall-clock time is the RTC time at init plus the monotonic time since then.
+Dates are in UTC and the proleptic Gregorian calendar, and converted to and from
+Unix time (seconds since 1970-01-01T00:00:00Z)
+*/
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use super::{rtc, Instant};
+use crate::serial_println;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// the Unix time read from the RTC by init, and the monotonic time it was read at
+static RTC_UNIX_SECONDS: AtomicU64 = AtomicU64::new(0);
+static RTC_READ_AT: AtomicU64 = AtomicU64::new(0);
+
+
+// a date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,      // 1 to 12
+    pub day: u8,        // 1 to 31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32
+}
+
+impl DateTime {
+    // the date and time of a Unix time (since 1970)
+    pub fn from_unix_time(time: Duration) -> Self {
+        let seconds = time.as_secs();
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let seconds_of_day = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: (seconds_of_day / 60 % 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+            nanosecond: time.subsec_nanos()
+        }
+    }
+
+    // the Unix time of the date and time, which must not be before 1970
+    pub fn unix_time(&self) -> Duration {
+        let days = days_from_civil(self.year, self.month, self.day);
+        let seconds = days * SECONDS_PER_DAY
+            + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64;
+        Duration::new(seconds, self.nanosecond)
+    }
+}
+
+// ISO 8601, e.g. 2024-02-29T23:45:30Z
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/*
+The number of days from 1970-01-01 to a date, and the inverse
+The algorithms count in eras of 400 years from 0000-03-01, so the leap day is the
+last day of a year (see http://howardhinnant.github.io/date_algorithms.html)
+*/
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = year as u64 - if month <= 2 { 1 } else { 0 };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // 719468 days from 0000-03-01 to 1970-01-01
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u8;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u16, month, day)
+}
+
+
+/*
+Read the RTC, this is called by time::init
+If the RTC time is invalid, the wall clock starts at Unix time 0
+*/
+pub fn init() {
+    let rtc_seconds = match rtc::read() {
+        Some(rtc_time) => rtc_time.unix_time().as_secs(),
+        None => {
+            serial_println!("WARNING: invalid RTC time, the wall clock starts at 1970-01-01");
+            0
+        }
+    };
+    RTC_READ_AT.store(Instant::now().as_nanos(), Ordering::SeqCst);
+    RTC_UNIX_SECONDS.store(rtc_seconds, Ordering::SeqCst);
+}
+
+// the current Unix time
+pub fn unix_time() -> Duration {
+    let since_read = Instant::now().duration_since(Instant::from_nanos(RTC_READ_AT.load(Ordering::SeqCst)));
+    Duration::from_secs(RTC_UNIX_SECONDS.load(Ordering::SeqCst)) + since_read
+}
+
+// the current date and time in UTC
+pub fn now() -> DateTime {
+    DateTime::from_unix_time(unix_time())
+}
+
+
+// test the conversion between dates and Unix time
+#[test_case]
+fn test_unix_time_conversion() {
+    let dates = [
+        (0, (1970, 1, 1, 0, 0, 0)),
+        (951_825_600, (2000, 2, 29, 12, 0, 0)),
+        (1_709_250_330, (2024, 2, 29, 23, 45, 30)),
+        (4_102_444_799, (2099, 12, 31, 23, 59, 59))
+    ];
+    for (seconds, (year, month, day, hour, minute, second)) in dates {
+        let date_time = DateTime { year, month, day, hour, minute, second, nanosecond: 0 };
+        assert_eq!(DateTime::from_unix_time(Duration::from_secs(seconds)), date_time);
+        assert_eq!(date_time.unix_time(), Duration::from_secs(seconds));
+    }
+}
+
+// test the wall clock follows the RTC
+#[test_case]
+fn test_wall_clock() {
+    let rtc_time = rtc::read().expect("invalid RTC time").unix_time();
+    let wall_time = unix_time();
+    // the RTC time is truncated to seconds
+    assert!(wall_time + Duration::from_secs(2) >= rtc_time && rtc_time + Duration::from_secs(2) >= wall_time);
+    assert!(now().year >= 2020);
+}
@@ -0,0 +1,252 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_core::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+/*
+Randomized stress tests of the heap allocators
+
+Each allocator gets its own memory arena, independent of the kernel heap, and runs the same
+sequence of allocations, reallocations and deallocations with mixed sizes and alignments.
+The sequence comes from a PRNG with a fixed seed, so a failure can be reproduced.
+Every allocation is checked to be aligned, inside the arena and not overlapping other
+allocations, and its contents are checked before it is reallocated or freed.
+*/
+
+use bootloader::{entry_point, BootInfo};
+use core::alloc::{GlobalAlloc, Layout};
+use core::arch::x86_64::_rdtsc;
+use core::panic::PanicInfo;
+use rust_core::allocator::Locked;
+use rust_core::memory::vma::{self, AreaKind};
+use rust_core::serial_println;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_core::allocator;
+    use rust_core::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+
+const SEED: u64 = 0x2545_F491_4F6C_DD1D;
+const ARENA_SIZE: u64 = 4 * 1024 * 1024;
+// the number of allocations alive at the same time
+const SLOTS: usize = 64;
+// all allocations are freed after each round, so the bump allocator can reuse its memory
+const ROUNDS: usize = 20;
+const OPERATIONS_PER_ROUND: usize = 400;
+const ALIGNMENTS: [usize; 8] = [1, 2, 4, 8, 8, 16, 64, 4096];
+
+
+// xorshift64* generator, deterministic for a seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // a number in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // mostly small sizes, some medium and a few large
+    fn size(&mut self) -> usize {
+        match self.below(20) {
+            0 => 1025 + self.below(15 * 1024),
+            1..=5 => 129 + self.below(896),
+            _ => 1 + self.below(128)
+        }
+    }
+}
+
+
+// a live allocation and the byte pattern written to it
+#[derive(Clone, Copy)]
+struct Allocation {
+    ptr: *mut u8,
+    layout: Layout,
+    pattern: u8
+}
+
+impl Allocation {
+    unsafe fn fill(&self) {
+        for i in 0..self.layout.size() {
+            self.ptr.add(i).write_volatile(self.pattern.wrapping_add(i as u8));
+        }
+    }
+
+    // check the first len bytes still have the pattern
+    unsafe fn check(&self, len: usize) {
+        for i in 0..len {
+            let value = self.ptr.add(i).read_volatile();
+            assert_eq!(value, self.pattern.wrapping_add(i as u8),
+                "allocation at {:p} corrupted at offset {}", self.ptr, i);
+        }
+    }
+}
+
+// the result of a stress run
+struct Report {
+    operations: usize,
+    cycles: u64
+}
+
+/*
+Run the random operation sequence on allocator, which owns the memory from
+arena_start to arena_end
+*/
+fn stress(allocator: &impl GlobalAlloc, arena_start: usize, arena_end: usize) -> Report {
+    let mut rng = Rng(SEED);
+    let mut slots: [Option<Allocation>; SLOTS] = [None; SLOTS];
+    let mut operations = 0;
+
+    // check the new allocation in slot against the arena and the other allocations
+    let validate = |slots: &[Option<Allocation>; SLOTS], slot: usize| {
+        let allocation = slots[slot].unwrap();
+        let start = allocation.ptr as usize;
+        let end = start + allocation.layout.size();
+        assert_eq!(start % allocation.layout.align(), 0, "misaligned allocation at {:#x}", start);
+        assert!(arena_start <= start && end <= arena_end, "allocation {:#x}-{:#x} outside arena", start, end);
+        for (i, other) in slots.iter().enumerate() {
+            if let Some(other) = other.filter(|_| i != slot) {
+                let other_start = other.ptr as usize;
+                let other_end = other_start + other.layout.size();
+                assert!(end <= other_start || other_end <= start,
+                    "allocation {:#x}-{:#x} overlaps {:#x}-{:#x}", start, end, other_start, other_end);
+            }
+        }
+    };
+
+    let start_time = unsafe { _rdtsc() };
+    for _ in 0..ROUNDS {
+        for _ in 0..OPERATIONS_PER_ROUND {
+            let slot = rng.below(SLOTS);
+            let pattern = rng.next() as u8;
+            match slots[slot] {
+                // allocate
+                None => {
+                    let align = ALIGNMENTS[rng.below(ALIGNMENTS.len())];
+                    let layout = Layout::from_size_align(rng.size(), align).unwrap();
+                    let ptr = unsafe { allocator.alloc(layout) };
+                    assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+                    slots[slot] = Some(Allocation { ptr, layout, pattern });
+                    validate(&slots, slot);
+                    unsafe { slots[slot].unwrap().fill() };
+                },
+                // reallocate, the contents up to the smaller size are kept
+                Some(allocation) if rng.below(3) == 0 => {
+                    let new_size = rng.size();
+                    let ptr = unsafe { allocator.realloc(allocation.ptr, allocation.layout, new_size) };
+                    assert!(!ptr.is_null(), "reallocation of {:?} to {} bytes failed", allocation.layout, new_size);
+                    let layout = Layout::from_size_align(new_size, allocation.layout.align()).unwrap();
+                    let moved = Allocation { ptr, layout, pattern: allocation.pattern };
+                    unsafe { moved.check(new_size.min(allocation.layout.size())) };
+                    slots[slot] = Some(Allocation { pattern, ..moved });
+                    validate(&slots, slot);
+                    unsafe { slots[slot].unwrap().fill() };
+                },
+                // free
+                Some(allocation) => {
+                    unsafe {
+                        allocation.check(allocation.layout.size());
+                        allocator.dealloc(allocation.ptr, allocation.layout);
+                    }
+                    slots[slot] = None;
+                }
+            }
+            operations += 1;
+        }
+
+        for slot in slots.iter_mut() {
+            if let Some(allocation) = slot.take() {
+                unsafe {
+                    allocation.check(allocation.layout.size());
+                    allocator.dealloc(allocation.ptr, allocation.layout);
+                }
+                operations += 1;
+            }
+        }
+    }
+
+    Report { operations, cycles: unsafe { _rdtsc() } - start_time }
+}
+
+/*
+Run the stress test on a new allocator of type A in a fresh arena, and print its throughput
+init initializes the allocator with the arena start and size
+*/
+fn run<A: GlobalAlloc>(name: &str, allocator: A, init: impl FnOnce(&A, usize, usize)) {
+    let area = vma::reserve(ARENA_SIZE, AreaKind::Other, "allocator stress").expect("no virtual memory for arena");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::map_region(area.start, area.size, flags).expect("failed to map arena");
+    let start = area.start.as_u64() as usize;
+    init(&allocator, start, ARENA_SIZE as usize);
+
+    let report = stress(&allocator, start, start + ARENA_SIZE as usize);
+    serial_println!();
+    serial_println!("{}: {} operations in {} cycles, {} cycles per operation",
+        name, report.operations, report.cycles, report.cycles / report.operations as u64);
+
+    // the allocator must not be used after its memory is released
+    drop(allocator);
+    vma::release(area.start);
+}
+
+
+#[test_case]
+fn bump_allocator() {
+    use rust_core::allocator::bump_allocator::BumpAllocator;
+    run("bump", Locked::new(BumpAllocator::new()), |allocator, start, size| unsafe {
+        allocator.lock().init(start, size);
+    });
+}
+
+#[test_case]
+fn linked_list_allocator() {
+    use rust_core::allocator::linked_list::LinkedListAllocator;
+    run("linked list", Locked::new(LinkedListAllocator::new()), |allocator, start, size| unsafe {
+        allocator.lock().init(start, size);
+    });
+}
+
+#[test_case]
+fn fixed_size_block_allocator() {
+    use rust_core::allocator::fixed_size_block::FixedSizeBlockAllocator;
+    run("fixed-size block", Locked::new(FixedSizeBlockAllocator::new()), |allocator, start, size| unsafe {
+        allocator.lock().init(start, size);
+    });
+}
+
+#[test_case]
+fn slab_allocator() {
+    use rust_core::allocator::slab::SlabAllocator;
+    run("slab", Locked::new(SlabAllocator::new()), |allocator, start, size| unsafe {
+        allocator.lock().init(start, size);
+    });
+}
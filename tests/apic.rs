@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_core::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::interrupts::{self, apic, InterruptController, InterruptIndex};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_core::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    interrupts::init_controller(InterruptController::Apic);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+
+// test QEMU has an APIC that replaced the 8259 PICs
+#[test_case]
+fn apic_enabled() {
+    assert!(apic::is_supported());
+    assert_eq!(interrupts::active_controller(), InterruptController::Apic);
+    assert!(apic::is_enabled());
+    // selecting the 8259 PICs later does not switch back
+    assert_eq!(interrupts::init_controller(InterruptController::Pic8259), InterruptController::Apic);
+}
+
+// test the timer and keyboard IRQs are routed to their vectors, unmasked
+#[test_case]
+fn redirection_entries() {
+    const MASKED: u64 = 1 << 16;
+    let apic_id = apic::local_apic().unwrap().id() as u64;
+
+    // the MADT of QEMU connects the timer IRQ 0 to GSI 2
+    let timer_route = apic::irq_route(0);
+    assert_eq!(timer_route.gsi, 2);
+    let timer = apic::redirection_entry(timer_route.gsi).unwrap();
+    assert_eq!(timer & 0xFF, InterruptIndex::Timer as u64);
+    assert_eq!(timer & MASKED, 0);
+    assert_eq!(timer >> 56, apic_id);
+    let keyboard = apic::redirection_entry(apic::irq_route(1).gsi).unwrap();
+    assert_eq!(keyboard & 0xFF, InterruptIndex::Keyboard as u64);
+    assert_eq!(keyboard & MASKED, 0);
+    // the other inputs are masked, IRQ 0 is connected to GSI 2 instead of GSI 0
+    assert_ne!(apic::redirection_entry(0).unwrap() & MASKED, 0);
+}
+
+// test timer interrupts keep arriving, which needs the EOI of each interrupt
+#[test_case]
+fn timer_interrupts() {
+    // hlt only returns after an interrupt, without EOI the second timer interrupt never comes
+    for _ in 0..10 {
+        x86_64::instructions::hlt();
+    }
+}
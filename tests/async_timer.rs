@@ -0,0 +1,132 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::cell::RefCell;
+use core::panic::PanicInfo;
+use core::time::Duration;
+use futures_util::future::join;
+use futures_util::stream::StreamExt;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use rust_core::task::{Task, executor::Executor, timer};
+use rust_core::time::{self, Instant};
+
+/*
+The timer futures need the executor, which never returns. So the tests run in
+one task, and the task exits QEMU after the last test
+*/
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_core::allocator;
+    use rust_core::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(run_tests()));
+    executor.run();
+}
+
+async fn run_tests() {
+    serial_println!("Running 5 tests");
+    sleep_duration().await;
+    sleep_order().await;
+    interval_period().await;
+    timeout_elapsed().await;
+    timeout_completed().await;
+    exit_qemu(QemuExitCode::Success);
+}
+
+
+// a sleep takes at least its duration, and two ticks longer at most
+// start is the time of the last tick, so the sleep adds a tick to measure from it
+async fn sleep_duration() {
+    serial_print!("async_timer::sleep_duration\t");
+    let start = Instant::now();
+    timer::sleep(Duration::from_millis(20)).await;
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(20) + time::tick_period());
+    // one more tick if the executor polls the task a tick late
+    assert!(elapsed <= Duration::from_millis(20) + 3 * time::tick_period());
+    assert_eq!(timer::pending_timers(), 0);
+
+    // a deadline in the past completes immediately
+    timer::sleep_until(start).await;
+    serial_println!("[OK]");
+}
+
+// concurrent sleeps complete in order of their deadlines
+async fn sleep_order() {
+    serial_print!("async_timer::sleep_order\t");
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let long = {
+        let order = order.clone();
+        async move {
+            timer::sleep(Duration::from_millis(30)).await;
+            order.borrow_mut().push(2);
+        }
+    };
+    let short = {
+        let order = order.clone();
+        async move {
+            timer::sleep(Duration::from_millis(10)).await;
+            order.borrow_mut().push(1);
+        }
+    };
+    join(long, short).await;
+    assert_eq!(*order.borrow(), [1, 2]);
+    serial_println!("[OK]");
+}
+
+// an interval yields at multiples of its period
+async fn interval_period() {
+    serial_print!("async_timer::interval_period\t");
+    let period = Duration::from_millis(5);
+    let mut interval = timer::interval(period);
+    let start = interval.next().await.unwrap();
+    for i in 1..=4 {
+        let instant = interval.next().await.unwrap();
+        assert_eq!(instant, start + i * period);
+        assert!(Instant::now() >= instant);
+    }
+    serial_println!("[OK]");
+}
+
+// a future that takes too long is cancelled and its sleep is removed
+async fn timeout_elapsed() {
+    serial_print!("async_timer::timeout_elapsed\t");
+    let result = timer::timeout(Duration::from_millis(10), timer::sleep(Duration::from_secs(10))).await;
+    assert_eq!(result, Err(timer::Elapsed));
+    assert_eq!(timer::pending_timers(), 0);
+    serial_println!("[OK]");
+}
+
+// a future that completes in time returns its output
+async fn timeout_completed() {
+    serial_print!("async_timer::timeout_completed\t");
+    let result = timer::timeout(Duration::from_millis(50), async {
+        timer::sleep(Duration::from_millis(5)).await;
+        42
+    }).await;
+    assert_eq!(result, Ok(42));
+    serial_println!("[OK]");
+}
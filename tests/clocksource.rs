@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_core::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use rust_core::acpi;
+use rust_core::time::{self, clocksource::{self, ClockSource}, hpet, tsc};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_core::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    clocksource::init();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+// wait for count timer interrupts
+fn wait_ticks(count: u64) {
+    let start = time::ticks();
+    while time::ticks() < start + count {
+        x86_64::instructions::hlt();
+    }
+}
+
+
+// test the HPET of QEMU is found in the ACPI tables at its standard address
+#[test_case]
+fn acpi_hpet_table() {
+    assert!(acpi::rsdp_address().is_some());
+    assert!(acpi::find_table(b"HPET").is_some());
+    assert!(acpi::find_table(b"NONE").is_none());
+    assert_eq!(hpet::find().unwrap().as_u64(), 0xFED0_0000);
+}
+
+// test the HPET counter runs at 10 MHz or more
+#[test_case]
+fn hpet_counter() {
+    let hpet = hpet::get().expect("no HPET");
+    assert!(hpet.frequency() >= 10_000_000);
+    let start = hpet.read();
+    wait_ticks(2);
+    assert!(hpet.read() > start);
+}
+
+// test the TSC is calibrated against the HPET, and agrees with the PIT ticks
+#[test_case]
+fn tsc_calibration() {
+    let tsc = tsc::get().expect("no TSC");
+    assert_eq!(tsc.reference(), tsc::Reference::Hpet);
+    assert!(tsc.frequency() > 100_000_000);
+
+    wait_ticks(1);
+    let start = tsc.read();
+    wait_ticks(20);
+    let elapsed = tsc.counts_to_nanos(tsc.read() - start);
+    let expected = 20 * time::tick_period().as_nanos() as u64;
+    assert!(elapsed > expected * 3 / 4 && elapsed < expected * 5 / 4);
+}
+
+// test the calibration against the PIT agrees with the calibration against the HPET
+#[test_case]
+fn tsc_pit_calibration() {
+    let hpet_frequency = tsc::calibrate(tsc::Reference::Hpet).expect("no HPET");
+    let pit_frequency = tsc::calibrate(tsc::Reference::Pit).expect("no TSC");
+    // the emulated timers of QEMU are read with port and MMIO accesses, allow 10%
+    let difference = hpet_frequency.abs_diff(pit_frequency);
+    assert!(difference < hpet_frequency / 10);
+}
+
+// test the selected clock source never goes backwards and has better than tick precision
+#[test_case]
+fn clocksource_resolution() {
+    assert_ne!(clocksource::current().name(), "ticks");
+    let mut last = clocksource::nanos();
+    for _ in 0..1000 {
+        let now = clocksource::nanos();
+        assert!(now >= last);
+        last = now;
+    }
+
+    let ((), empty) = clocksource::measure(|| ());
+    assert!(empty < time::tick_period());
+}
+
+// test measure agrees with the tick counter
+#[test_case]
+fn measure_ticks() {
+    wait_ticks(1);
+    let ((), elapsed) = clocksource::measure(|| wait_ticks(5));
+    assert!(elapsed >= 4 * time::tick_period());
+    assert!(elapsed <= 6 * time::tick_period() + Duration::from_micros(500));
+}
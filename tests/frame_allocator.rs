@@ -0,0 +1,173 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_core::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::memory::{BitmapFrameAllocator, MemoryZone};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::PhysAddr;
+
+// test cases cannot take arguments, so the allocator under test is stored in a static
+static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+
+// unit tests for physical frame allocation
+// test the free count changes on allocation and deallocation
+#[test_case]
+fn allocate_and_free() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("out of frames");
+    assert!(!allocator.is_free(frame));
+    assert_eq!(allocator.free_frames(), free - 1);
+    assert_eq!(allocator.used_frames() + allocator.free_frames(), allocator.total_frames());
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(allocator.is_free(frame));
+    assert_eq!(allocator.free_frames(), free);
+}
+
+// test allocated frames are distinct
+#[test_case]
+fn distinct_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+
+    let mut frames: [Option<PhysFrame>; 64] = [None; 64];
+    for slot in frames.iter_mut() {
+        *slot = allocator.allocate_frame();
+    }
+    for (i, a) in frames.iter().enumerate() {
+        for b in frames[i + 1..].iter() {
+            assert_ne!(a.unwrap(), b.unwrap());
+        }
+    }
+    for frame in frames.iter() {
+        unsafe { allocator.deallocate_frame(frame.unwrap()) };
+    }
+}
+
+// test freed frames can be allocated again
+#[test_case]
+fn frame_reuse() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    // allocating more frames than 4 times the free count only works if they are reused
+    for _ in 0..free * 4 {
+        let frame: PhysFrame = allocator.allocate_frame().expect("frames are not reused");
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
+
+// test contiguous and aligned allocation
+#[test_case]
+fn contiguous_frames() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    // 16 frames aligned to 64 KiB
+    let range = allocator.allocate_contiguous(16, 16).expect("no contiguous frames");
+    assert_eq!(range.start.start_address().as_u64() % (16 * 4096), 0);
+    assert_eq!(range.end - range.start, 16);
+    for frame in range {
+        assert!(!allocator.is_free(frame));
+    }
+    assert_eq!(allocator.free_frames(), free - 16);
+
+    unsafe { allocator.deallocate_contiguous(range) };
+    assert_eq!(allocator.free_frames(), free);
+}
+
+// test frames allocated in a zone are below the zone limit
+#[test_case]
+fn zone_allocation() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let dma_free = allocator.zone_free_frames(MemoryZone::Dma);
+    assert!(dma_free <= allocator.zone_free_frames(MemoryZone::Dma32));
+    assert_eq!(allocator.zone_free_frames(MemoryZone::Normal), allocator.free_frames());
+
+    let frame = allocator.allocate_frame_in(MemoryZone::Dma).expect("no frame in DMA zone");
+    assert!(frame.start_address().as_u64() < MemoryZone::Dma.limit());
+    assert_eq!(allocator.zone_free_frames(MemoryZone::Dma), dma_free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+
+    // DMA32 allocations leave the DMA zone free while there is memory above 16 MiB
+    let range = allocator.allocate_contiguous_in(4, 4, MemoryZone::Dma32).expect("no frames in DMA32 zone");
+    assert!(range.end.start_address().as_u64() <= MemoryZone::Dma32.limit());
+    assert!(range.start.start_address().as_u64() >= MemoryZone::Dma.limit());
+    let frame = allocator.allocate_frame_in(MemoryZone::Dma32).expect("no frame in DMA32 zone");
+    assert!(frame.start_address().as_u64() >= MemoryZone::Dma.limit());
+    assert_eq!(allocator.zone_free_frames(MemoryZone::Dma), dma_free);
+    unsafe {
+        allocator.deallocate_frame(frame);
+        allocator.deallocate_contiguous(range);
+    }
+
+    // allocations without zone leave the DMA zone free while there is other memory
+    if allocator.free_frames() > dma_free {
+        let frame: PhysFrame = allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().as_u64() >= MemoryZone::Dma.limit());
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.zone_free_frames(MemoryZone::Dma), dma_free);
+}
+
+// test a shared frame is freed after its last reference is dropped
+#[test_case]
+fn frame_references() {
+    let mut guard = FRAME_ALLOCATOR.lock();
+    let allocator = guard.as_mut().unwrap();
+    let free = allocator.free_frames();
+
+    let frame: PhysFrame = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.reference_count(frame), 1);
+    allocator.add_reference(frame);
+    assert_eq!(allocator.reference_count(frame), 2);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(!allocator.is_free(frame));
+    assert_eq!(allocator.reference_count(frame), 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert!(allocator.is_free(frame));
+    assert_eq!(allocator.reference_count(frame), 0);
+    assert_eq!(allocator.free_frames(), free);
+
+    // frames after the end of memory (e.g. the I/O APIC registers) are not managed
+    let mmio: PhysFrame = PhysFrame::containing_address(PhysAddr::new(0xFEC0_0000));
+    assert!(!allocator.is_free(mmio));
+    assert_eq!(allocator.reference_count(mmio), 0);
+    unsafe { allocator.deallocate_frame(mmio) };
+    assert_eq!(allocator.free_frames(), free);
+}
@@ -0,0 +1,46 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use rust_core::memory::stack::KernelStack;
+
+
+// the test successes if the page fault handler reports the overflow of the test stack
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "stack overflow in stack guard page test")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_core::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    rust_core::gdt::init_stacks();
+
+    guard_page_hit();
+    serial_println!("[guard page not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// write directly below the stack memory, as an overflowing stack would
+fn guard_page_hit() {
+    serial_print!("guard_page::guard_page_hit \t");
+    let stack = KernelStack::new(4096 * 4, "guard page test").expect("failed to allocate stack");
+    let top = stack.top().as_mut_ptr::<u64>();
+    unsafe { top.sub(1).write_volatile(1) };   // the stack memory is mapped
+
+    let below = (stack.bottom() - 8u64).as_mut_ptr::<u64>();
+    unsafe { below.write_volatile(1) };
+}
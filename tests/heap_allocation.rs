@@ -8,23 +8,31 @@ extern crate alloc;
 
 use bootloader::{entry_point, BootInfo};
 use rust_core::allocator::HEAP_SIZE;
+use rust_core::serial_println;
+use rust_core::allocator::slab::ObjectCache;
+use rust_core::allocator::Locked;
+use rust_core::allocator::linked_list::{FitStrategy, LinkedListAllocator};
 use core::panic::PanicInfo;
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{alloc::{GlobalAlloc, Layout}, boxed::Box, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use rust_core::allocator;
-    use rust_core::memory::{self, BootInfoFrameAllocator};
+    use rust_core::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     rust_core::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
     };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::init_memory_manager(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    // the heap allocator is selected with cargo features
+    serial_println!("heap allocator: {}", allocator::ALLOCATOR_NAME);
 
     test_main();
     loop {}
@@ -75,4 +83,232 @@ fn many_boxes_long_lived() {
         assert_eq!(*x, i);
     }
     assert_eq!(*long_lived, 1);
-}
\ No newline at end of file
+}
+
+// test the heap grows when the allocation is larger than the initial heap
+#[test_case]
+fn heap_growth() {
+    use rust_core::allocator;
+
+    let n = 4 * HEAP_SIZE;
+    let mut vec = Vec::with_capacity(n);
+    for i in 0..n {
+        vec.push(i as u8);
+    }
+    assert!(allocator::heap_size() > HEAP_SIZE);
+    assert_eq!(vec[n - 1], (n - 1) as u8);
+}
+
+// test heap statistics track allocations and deallocations
+#[test_case]
+fn heap_stats() {
+    use rust_core::allocator;
+
+    let before = allocator::stats();
+    let value = Box::new([0u64; 16]);
+    let during = allocator::stats();
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.peak >= during.allocated);
+
+    drop(value);
+    let after = allocator::stats();
+    // with heap-debug, the header and red zones are counted as allocated, and the
+    // deallocation is delayed until the allocation leaves the quarantine
+    #[cfg(not(feature = "heap-debug"))]
+    {
+        assert_eq!(during.allocated, before.allocated + 128);
+        assert_eq!(after.deallocations, before.deallocations + 1);
+        assert_eq!(after.allocated, before.allocated);
+    }
+    #[cfg(feature = "heap-debug")]
+    {
+        assert!(during.allocated >= before.allocated + 128);
+        assert!(after.deallocations >= before.deallocations);
+        assert!(after.allocated >= before.allocated);
+    }
+}
+
+// a named object cache for the slab tests
+static OBJECT_CACHE: ObjectCache<[u64; 3]> = ObjectCache::new("test-object");
+
+// test objects from an object cache are distinct and keep their values
+#[test_case]
+fn object_cache_allocation() {
+    let mut objects = Vec::new();
+    for i in 0..1000 {
+        objects.push(OBJECT_CACHE.alloc([i; 3]));
+    }
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(**object, [i as u64; 3]);
+    }
+    let stats = OBJECT_CACHE.stats();
+    assert_eq!(stats.objects_in_use, 1000);
+    assert!(stats.slabs > 1);
+}
+
+// test empty slabs are released when objects are freed
+#[test_case]
+fn object_cache_release() {
+    let objects: Vec<_> = (0..1000).map(|i| OBJECT_CACHE.alloc([i; 3])).collect();
+    drop(objects);
+    OBJECT_CACHE.shrink();
+    let stats = OBJECT_CACHE.stats();
+    assert_eq!(stats.objects_in_use, 0);
+    assert_eq!(stats.slabs, 0);
+}
+
+// test reallocation of large allocations keeps their contents
+#[test_case]
+fn large_realloc() {
+    let mut vec: Vec<u8> = (0..4096).map(|i| i as u8).collect();
+    for size in [8192, 16384, 65536] {
+        vec.reserve_exact(size - vec.len());
+        assert!(vec.iter().enumerate().all(|(i, &x)| x == i as u8));
+    }
+    vec.truncate(100);
+    vec.shrink_to_fit();
+    assert!(vec.iter().enumerate().all(|(i, &x)| x == i as u8));
+}
+
+// the size of the local heap of the linked list allocator tests
+const ARENA_SIZE: usize = 1024;
+
+/*
+Create a linked list allocator on a new arena, return it with the arena start
+The arena is taken from (and leaked on) the kernel heap, so the results of the tests
+do not depend on the kernel heap allocator
+*/
+fn arena_allocator(strategy: FitStrategy) -> (Locked<LinkedListAllocator>, usize) {
+    let start = Vec::leak(alloc::vec![0u64; ARENA_SIZE / 8]).as_mut_ptr() as usize;
+    let allocator = Locked::new(LinkedListAllocator::with_strategy(strategy));
+    unsafe { allocator.lock().init(start, ARENA_SIZE) };
+    (allocator, start)
+}
+
+fn block(size: usize) -> Layout {
+    Layout::from_size_align(size, 8).unwrap()
+}
+
+/*
+Allocate blocks of 64, 256, 64, 128 and 64 bytes and free the 256 and 128 byte blocks
+Return the addresses of the two free blocks, and the start of the free memory after the blocks
+*/
+fn fragment(allocator: &Locked<LinkedListAllocator>) -> (usize, usize, usize) {
+    let blocks = [64, 256, 64, 128, 64].map(|size| unsafe { allocator.alloc(block(size)) } as usize);
+    assert!(blocks.iter().all(|&addr| addr != 0));
+    unsafe {
+        allocator.dealloc(blocks[1] as *mut u8, block(256));
+        allocator.dealloc(blocks[3] as *mut u8, block(128));
+    }
+    (blocks[1], blocks[3], blocks[4] + 64)
+}
+
+// test first fit takes the first large enough free region
+#[test_case]
+fn linked_list_first_fit() {
+    let (allocator, _) = arena_allocator(FitStrategy::FirstFit);
+    let (large, _, _) = fragment(&allocator);
+    assert_eq!(unsafe { allocator.alloc(block(100)) } as usize, large);
+}
+
+// test best fit takes the smallest large enough free region
+#[test_case]
+fn linked_list_best_fit() {
+    let (allocator, _) = arena_allocator(FitStrategy::BestFit);
+    let (_, small, _) = fragment(&allocator);
+    assert_eq!(unsafe { allocator.alloc(block(100)) } as usize, small);
+}
+
+// test next fit continues after the previous allocation, and wraps around at the end
+#[test_case]
+fn linked_list_next_fit() {
+    let (allocator, _) = arena_allocator(FitStrategy::NextFit);
+    let (large, _, rest) = fragment(&allocator);
+    // first fit would take the free 256 byte block
+    let first = unsafe { allocator.alloc(block(100)) } as usize;
+    assert_eq!(first, rest);
+    // the 100 byte block is padded to 104 bytes, so 448 - 104 - 300 = 44 bytes remain
+    // after this allocation
+    let second = unsafe { allocator.alloc(block(300)) } as usize;
+    assert_eq!(second, first + 104);
+    // nothing after the previous allocation is large enough, start from the beginning
+    assert_eq!(unsafe { allocator.alloc(block(100)) } as usize, large);
+}
+
+// test freed blocks are merged with adjacent free regions, in any order of deallocation
+#[test_case]
+fn linked_list_coalescing() {
+    let (allocator, start) = arena_allocator(FitStrategy::FirstFit);
+    let blocks = [128, 128, 128, 128].map(|size| unsafe { allocator.alloc(block(size)) });
+    let free_regions = || allocator.lock().free_region_stats().regions;
+    assert_eq!(free_regions(), 1);
+
+    unsafe {
+        // the free regions are kept in address order even when freed out of order
+        allocator.dealloc(blocks[2], block(128));
+        allocator.dealloc(blocks[0], block(128));
+        assert_eq!(free_regions(), 3);
+        // the block between two free regions merges with both
+        allocator.dealloc(blocks[1], block(128));
+        assert_eq!(free_regions(), 2);
+        // the last block merges with the first three blocks and the rest of the arena
+        allocator.dealloc(blocks[3], block(128));
+    }
+    let stats = allocator.lock().free_region_stats();
+    assert_eq!((stats.regions, stats.free_bytes, stats.largest), (1, ARENA_SIZE, ARENA_SIZE));
+    // the merged region starts at the start of the arena
+    assert_eq!(unsafe { allocator.alloc(block(ARENA_SIZE)) } as usize, start);
+}
+
+// test realloc grows into the free region after the allocation without moving it
+#[test_case]
+fn linked_list_realloc_in_place() {
+    let (allocator, _) = arena_allocator(FitStrategy::FirstFit);
+    unsafe {
+        let first = allocator.alloc(block(64));
+        let second = allocator.alloc(block(64));
+        let last = allocator.alloc(block(64));
+        allocator.dealloc(second, block(64));
+        first.write_bytes(0xAB, 64);
+
+        // grow into the freed block
+        let grown = allocator.realloc(first, block(64), 128);
+        assert_eq!(grown, first);
+        assert!(core::slice::from_raw_parts(grown, 64).iter().all(|&x| x == 0xAB));
+        assert_eq!(allocator.lock().free_region_stats().regions, 1);
+
+        // shrink back, the released end is free again
+        let shrunk = allocator.realloc(grown, block(128), 64);
+        assert_eq!(shrunk, first);
+        assert_eq!(allocator.alloc(block(64)), second);
+
+        // there is no free memory after the allocation, it has to move
+        let moved = allocator.realloc(first, block(64), 128);
+        assert_ne!(moved, first);
+        assert!(moved as usize > last as usize);
+    }
+}
+
+// the number of calls of the OOM handler registered by the test
+static OOM_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn count_oom(_layout: Layout) -> bool {
+    OOM_CALLS.fetch_add(1, Ordering::SeqCst);
+    false
+}
+
+// test OOM handlers are called when an allocation exceeds the heap limit
+#[test_case]
+fn oom_handler() {
+    use rust_core::allocator::{self, oom};
+
+    oom::register_oom_handler(count_oom);
+    let limit = allocator::heap_limit();
+    allocator::set_heap_limit(allocator::heap_size());
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve_exact(limit).is_err());
+    assert!(OOM_CALLS.load(Ordering::SeqCst) > 0);
+
+    allocator::set_heap_limit(limit);
+}
@@ -0,0 +1,39 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+
+// the test successes if the heap debugging allocator panics on double free
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[OK]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    double_free();
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn double_free() {
+    serial_print!("heap_debug::double_free \t");
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
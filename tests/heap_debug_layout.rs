@@ -0,0 +1,37 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+
+// the test successes if the heap debugging allocator reports a mismatched layout
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "mismatched layout")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    mismatched_layout();
+    serial_println!("[mismatched layout not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// free an allocation with a different size than it was allocated with
+fn mismatched_layout() {
+    serial_print!("heap_debug_layout::mismatched_layout \t");
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, Layout::from_size_align(32, 8).unwrap());
+    }
+}
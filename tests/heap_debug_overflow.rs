@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+
+// the test successes if the heap debugging allocator reports a buffer overflow
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "buffer overflow")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    buffer_overflow();
+    serial_println!("[buffer overflow not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// write one byte after the allocation, the red zone is checked on dealloc
+fn buffer_overflow() {
+    serial_print!("heap_debug_overflow::buffer_overflow \t");
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.add(64).write_volatile(0);
+        dealloc(ptr, layout);
+    }
+}
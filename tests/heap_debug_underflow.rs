@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+
+// the test successes if the heap debugging allocator reports a buffer underflow
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "buffer underflow")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    buffer_underflow();
+    serial_println!("[buffer underflow not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// write one byte before the allocation, the red zone is checked on dealloc
+fn buffer_underflow() {
+    serial_print!("heap_debug_underflow::buffer_underflow \t");
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.sub(1).write_volatile(0);
+        dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use rust_core::allocator::debug::POISON;
+
+
+// the test successes if the heap debugging allocator reports a use after free
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "use after free")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    use_after_free();
+    serial_println!("[use after free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+/*
+Freed memory reads as the poison pattern. A write to freed memory is detected when the
+allocation leaves the quarantine, so enough allocations are freed after it to evict it
+*/
+fn use_after_free() {
+    serial_print!("heap_debug_use_after_free::use_after_free \t");
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0x11, 64);
+        dealloc(ptr, layout);
+        assert!((0..64).all(|offset| ptr.add(offset).read_volatile() == POISON));
+
+        ptr.add(10).write_volatile(0);
+        for _ in 0..64 {
+            dealloc(alloc(layout), layout);
+        }
+    }
+}
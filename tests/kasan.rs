@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_core::{QemuExitCode, exit_qemu, serial_print, serial_println};
+use rust_core::allocator::kasan;
+
+
+// the test successes if the check of freed memory reports a use after free
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::expected_panic_handler(info, "KASAN: use after free")
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init_test_heap(boot_info);
+
+    shadow_tracking();
+    use_after_free();
+    serial_println!("[use after free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// the shadow memory follows allocations, deallocations and heap growth
+fn shadow_tracking() {
+    serial_print!("kasan::shadow_tracking \t");
+    let data = Box::new([1u8; 13]);
+    let ptr = data.as_ptr();
+    assert!(kasan::is_accessible(ptr, 13));
+    assert!(!kasan::is_accessible(ptr, 14));
+    assert!(!kasan::is_accessible(unsafe { ptr.add(13) }, 1));
+    kasan::check_read(ptr, 13);
+
+    // memory in the grown heap is tracked
+    let mut vec: Vec<u64> = Vec::with_capacity(64 * 1024);
+    vec.push(1);
+    let vec_ptr = vec.as_ptr() as *const u8;
+    assert!(kasan::is_accessible(vec_ptr, 64 * 1024 * 8));
+    drop(vec);
+    assert!(!kasan::is_accessible(vec_ptr, 1));
+    serial_println!("[OK]");
+}
+
+// a read of freed memory is reported
+fn use_after_free() {
+    serial_print!("kasan::use_after_free \t");
+    let data = Box::new(42u64);
+    let ptr = &*data as *const u64 as *const u8;
+    drop(data);
+    kasan::check_read(ptr, 8);
+}
@@ -0,0 +1,359 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_core::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_core::memory::{self, BitmapFrameAllocator, MEMORY_MANAGER};
+use rust_core::memory::vma::{self, AreaKind};
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use x86_64::VirtAddr;
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_core::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::SeqCst);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::init_memory_manager(mapper, frame_allocator);
+    memory::remap_kernel_text();
+    memory::enforce_wx();
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_core::test_panic_handler(info)
+}
+
+
+// return the size of the page containing addr, None if addr is not mapped
+fn page_size(addr: VirtAddr) -> Option<u64> {
+    let memory_manager = MEMORY_MANAGER.lock();
+    match memory_manager.as_ref().unwrap().mapper.translate(addr) {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. } => Some(4096),
+        TranslateResult::Mapped { frame: MappedFrame::Size2MiB(_), .. } => Some(0x20_0000),
+        TranslateResult::Mapped { frame: MappedFrame::Size1GiB(_), .. } => Some(0x4000_0000),
+        _ => None
+    }
+}
+
+
+// test the aligned part of a range is mapped with a 2 MiB page, and the rest with 4 KiB pages
+#[test_case]
+fn huge_page_mapping() {
+    // areas of 2 MiB or more are 2 MiB aligned
+    let area = vma::reserve(0x60_0000, AreaKind::Other, "huge page mapping test").unwrap();
+    let region = area.start + 0x20_0000u64;
+    let start = region - 4096u64;
+    let size = 0x20_0000 + 2 * 4096;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    vma::map_region(start, size, flags).expect("mapping failed");
+
+    assert_eq!(page_size(start), Some(4096));
+    assert_eq!(page_size(region), Some(0x20_0000));
+    assert_eq!(page_size(region + 0x1F_F000u64), Some(0x20_0000));
+    assert_eq!(page_size(region + 0x20_0000u64), Some(4096));
+    assert_eq!(page_size(region + 0x20_1000u64), None);
+
+    // the mapped memory is usable
+    let ptr = start.as_mut_ptr::<u64>();
+    let count = size as usize / 8;
+    for i in 0..count {
+        unsafe { ptr.add(i).write_volatile(i as u64) };
+    }
+    for i in 0..count {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64);
+    }
+
+    // releasing the area unmaps the pages
+    vma::release(area.start);
+}
+
+// test manual page table walk translates addresses inside a 2 MiB page
+#[test_case]
+fn huge_page_translation() {
+    let phys_mem_offset = VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::SeqCst));
+    let area = vma::reserve(0x20_0000, AreaKind::Other, "huge page translation test").unwrap();
+    let base = area.start;
+    vma::map_region(base, area.size, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("mapping failed");
+    assert_eq!(page_size(base), Some(0x20_0000));
+    let base_phys = unsafe { memory::translate_addr(base, phys_mem_offset) }
+        .expect("huge page not mapped");
+    assert!(base_phys.is_aligned(0x20_0000u64));
+
+    for &offset in &[8u64, 0x1234, 0x10_0000, 0x1F_FFFF] {
+        let phys = unsafe { memory::translate_addr(base + offset, phys_mem_offset) };
+        assert_eq!(phys, Some(base_phys + offset));
+        let memory_manager = MEMORY_MANAGER.lock();
+        let mapper = &memory_manager.as_ref().unwrap().mapper;
+        assert_eq!(mapper.translate_addr(base + offset), phys);
+    }
+
+    vma::release(area.start);
+}
+
+// test reserved areas do not overlap
+#[test_case]
+fn reserved_areas() {
+    let a = vma::reserve(0x3000, AreaKind::Stack, "area a").unwrap();
+    let b = vma::reserve(0x3000, AreaKind::Stack, "area b").unwrap();
+    assert!(a.end() <= b.start || b.end() <= a.start);
+    assert!(vma::reserve_at(a.start, 0x1000, AreaKind::Other, "overlap").is_none());
+    assert_eq!(vma::find_area(a.start + 0x2000u64).unwrap().name, "area a");
+
+    vma::release(a.start);
+    vma::release(b.start);
+    assert!(vma::find_area(a.start).is_none());
+}
+
+// test unmapping a region frees its frames
+#[test_case]
+fn unmap_region() {
+    let area = vma::reserve(0x1_0000, AreaKind::Other, "unmap test").unwrap();
+    let free_frames = || MEMORY_MANAGER.lock().as_ref().unwrap().frame_allocator.free_frames();
+
+    vma::map_region(area.start, area.size, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("mapping failed");
+    let mapped = free_frames();
+    assert_eq!(page_size(area.start), Some(4096));
+
+    vma::unmap_region(area.start, area.size);
+    assert_eq!(free_frames(), mapped + 16);
+    assert_eq!(page_size(area.start), None);
+    vma::release(area.start);
+}
+
+// test pages of a demand paged area are mapped with zeroed frames on first access
+#[test_case]
+fn demand_paging() {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let area = vma::reserve_demand_paged(0x10_0000, AreaKind::Other, "demand paging test", flags)
+        .unwrap();
+    let page = area.start + 0x5000u64;
+    assert_eq!(page_size(page), None);
+
+    // a read maps the page
+    let ptr = page.as_mut_ptr::<u64>();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    assert_eq!(page_size(page), Some(4096));
+
+    // a write to a new page maps it, other pages stay unmapped
+    let next = (page + 4096u64).as_mut_ptr::<u64>();
+    unsafe { next.add(7).write_volatile(42) };
+    assert_eq!(unsafe { next.add(7).read_volatile() }, 42);
+    assert!((0..512).filter(|&i| i != 7).all(|i| unsafe { next.add(i).read_volatile() } == 0));
+    assert_eq!(page_size(area.start), None);
+
+    vma::release(area.start);
+    assert_eq!(page_size(page), None);
+}
+
+// test the kernel code is mapped read-only and executable after remapping
+#[test_case]
+fn kernel_text_read_only() {
+    memory::remap_kernel_text();
+    let memory_manager = MEMORY_MANAGER.lock();
+    let mapper = &memory_manager.as_ref().unwrap().mapper;
+    let code = VirtAddr::from_ptr(memory::remap_kernel_text as *const ());
+    match mapper.translate(code) {
+        TranslateResult::Mapped { flags, .. } => {
+            assert!(!flags.contains(PageTableFlags::WRITABLE));
+            assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+        },
+        _ => panic!("kernel code not mapped")
+    }
+}
+
+// test no page is writable and executable after boot, and the W^X audit finds new violations
+#[test_case]
+fn wx_audit() {
+    use rust_core::memory::page_table::audit_wx;
+
+    assert_eq!(audit_wx(), 0);
+    let area = vma::reserve(0x2000, AreaKind::Other, "W^X test").unwrap();
+    vma::map_region(area.start, area.size, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("mapping failed");
+    assert_eq!(audit_wx(), 2);
+    vma::unmap_region(area.start, area.size);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::map_region(area.start, area.size, flags).expect("mapping failed");
+    assert_eq!(audit_wx(), 0);
+    vma::release(area.start);
+}
+
+// test enforce_wx makes writable and executable pages non-executable
+#[test_case]
+fn wx_enforcement() {
+    use rust_core::memory::page_table::audit_wx;
+
+    let area = vma::reserve(0x2000, AreaKind::Other, "W^X enforcement test").unwrap();
+    vma::map_region(area.start, area.size, PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .expect("mapping failed");
+    assert_eq!(memory::enforce_wx(), 2);
+    assert_eq!(audit_wx(), 0);
+    // the pages stay writable
+    unsafe { area.start.as_mut_ptr::<u64>().write_volatile(42) };
+    vma::release(area.start);
+}
+
+// test a mapped region is found as one run by the page table walker
+#[test_case]
+fn page_table_runs() {
+    use rust_core::memory::page_table::{self, MappingRun};
+
+    let area = vma::reserve(0x4000, AreaKind::Other, "walker test").unwrap();
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    vma::map_region(area.start, area.size, flags).expect("mapping failed");
+
+    // the runs are sorted and do not overlap
+    let mut last_end = 0;
+    let mut found: Option<MappingRun> = None;
+    page_table::for_each_run(|run| {
+        assert!(run.start.as_u64() >= last_end);
+        last_end = run.end().as_u64();
+        if run.start <= area.start && area.start < run.end() {
+            found = Some(run);
+        }
+    });
+
+    // the frames may not be contiguous, so the run contains at least the first page
+    let run = found.expect("mapped region not found");
+    assert_eq!(run.page_size, 4096);
+    assert!(run.flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE));
+    vma::release(area.start);
+}
+
+// test MMIO accesses reach the physical memory and the frame is not freed on unmap
+#[test_case]
+fn mmio_mapping() {
+    use rust_core::memory::map_mmio;
+    use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+
+    // use a RAM frame as device memory, it is also accessible at the physical memory offset
+    let frame: PhysFrame = MEMORY_MANAGER.lock().as_mut().unwrap().frame_allocator
+        .allocate_frame().unwrap();
+    let phys_mem_offset = PHYS_MEM_OFFSET.load(Ordering::SeqCst);
+    let ram = (phys_mem_offset + frame.start_address().as_u64()) as *mut u32;
+    unsafe { ram.add(4).write_volatile(0x1234_5678) };
+
+    // the region starts in the middle of the page
+    let mmio = map_mmio(frame.start_address() + 8u64, 64).expect("MMIO mapping failed");
+    assert_eq!(mmio.read::<u32>(8), 0x1234_5678);
+    mmio.write::<u32>(12, 0xCAFE);
+    assert_eq!(unsafe { ram.add(5).read_volatile() }, 0xCAFE);
+
+    let flags = match MEMORY_MANAGER.lock().as_ref().unwrap().mapper.translate(mmio.virt_addr()) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("MMIO region not mapped")
+    };
+    assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+
+    let virt = mmio.virt_addr();
+    drop(mmio);
+    assert_eq!(page_size(virt), None);
+    let mut memory_manager = MEMORY_MANAGER.lock();
+    let frame_allocator = &mut memory_manager.as_mut().unwrap().frame_allocator;
+    assert!(!frame_allocator.is_free(frame));
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+// test writes to copy-on-write pages copy the shared frame
+#[test_case]
+fn copy_on_write() {
+    use rust_core::memory::cow::{self, COW_FLAG};
+
+    let page_flags = |addr: VirtAddr| match MEMORY_MANAGER.lock().as_ref().unwrap().mapper.translate(addr) {
+        TranslateResult::Mapped { frame, flags, .. } => (frame.start_address(), flags),
+        _ => panic!("page not mapped")
+    };
+    let reference_count = |addr: VirtAddr| {
+        let frame = x86_64::structures::paging::PhysFrame::containing_address(page_flags(addr).0);
+        MEMORY_MANAGER.lock().as_ref().unwrap().frame_allocator.reference_count(frame)
+    };
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let src = vma::reserve(0x2000, AreaKind::Other, "cow source").unwrap();
+    let dst = vma::reserve(0x2000, AreaKind::Other, "cow copy").unwrap();
+    vma::map_region(src.start, src.size, flags).expect("mapping failed");
+    let src_ptr = src.start.as_mut_ptr::<u64>();
+    let dst_ptr = dst.start.as_mut_ptr::<u64>();
+    unsafe { src_ptr.write_volatile(1) };
+
+    cow::share_region(src.start, dst.start, src.size).expect("sharing failed");
+    let (frame, shared_flags) = page_flags(dst.start);
+    assert_eq!(frame, page_flags(src.start).0);
+    assert!(shared_flags.contains(COW_FLAG) && !shared_flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(reference_count(src.start), 2);
+    assert_eq!(unsafe { dst_ptr.read_volatile() }, 1);
+
+    // the write to the copy gets a private frame
+    unsafe { dst_ptr.write_volatile(2) };
+    assert_eq!(unsafe { src_ptr.read_volatile() }, 1);
+    assert_eq!(unsafe { dst_ptr.read_volatile() }, 2);
+    assert_ne!(page_flags(dst.start).0, frame);
+    assert_eq!(reference_count(src.start), 1);
+
+    // the write to the last reference keeps the frame
+    unsafe { src_ptr.write_volatile(3) };
+    let (src_frame, src_flags) = page_flags(src.start);
+    assert_eq!(src_frame, frame);
+    assert!(src_flags.contains(PageTableFlags::WRITABLE) && !src_flags.contains(COW_FLAG));
+    assert_eq!(unsafe { dst_ptr.read_volatile() }, 2);
+
+    // the second page is still shared, releasing both areas frees it once
+    vma::release(src.start);
+    assert_eq!(reference_count(dst.start + 0x1000u64), 1);
+    vma::release(dst.start);
+}
+
+// test user pages of an address space are only visible while it is active,
+// and its frames are freed on drop
+#[test_case]
+fn address_space() {
+    use rust_core::memory::address_space::{self, AddressSpace, USER_START};
+
+    let free_frames = || MEMORY_MANAGER.lock().as_ref().unwrap().frame_allocator.free_frames();
+    let before = free_frames();
+    let user_page = VirtAddr::new(USER_START);
+
+    let mut space = AddressSpace::new().expect("no frame for address space");
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    space.map_user(user_page, 0x2000, flags).expect("mapping failed");
+    assert!(space.translate(user_page).is_some());
+    assert!(space.translate(user_page + 0x2000u64).is_none());
+    assert_eq!(page_size(user_page), None);
+    // the kernel mappings are shared
+    let kernel_addr = VirtAddr::from_ptr(&before);
+    assert_eq!(space.translate(kernel_addr),
+        MEMORY_MANAGER.lock().as_ref().unwrap().mapper.translate_addr(kernel_addr));
+
+    let ptr = user_page.as_mut_ptr::<u64>();
+    unsafe {
+        space.activate();
+        assert!(space.is_active());
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    address_space::activate_kernel();
+    assert!(!space.is_active());
+    assert_eq!(page_size(user_page), None);
+
+    drop(space);
+    assert_eq!(free_frames(), before);
+}